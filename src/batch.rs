@@ -0,0 +1,199 @@
+// `--batch` mode, which strips many notebooks in one process. Each notebook is sent as a
+// request frame on STDIN and gets a response frame on STDOUT, in the same order.
+//
+// A request is a JSON object `{"path": "a.ipynb", "contents": "<notebook>"}`, where `path`
+// is optional. A response is `{"path": ..., "contents": "<stripped notebook>", "changed": true}`,
+// with the `repairs` made to the notebook if there were any, or
+// `{"path": ..., "error": {"message": "...", "pointer": "/cells/2", "cell": 2}}`, where the
+// pointer and cell are those of the first schema violation, if that is what went wrong.
+// Frames are either one JSON object per line (`ndjson`) or a line with the length of the JSON
+// object in bytes followed by the object (`length_prefixed`).
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::stripoutlib;
+
+/// The largest length_prefixed frame that is read, so that a bad header fails instead of
+/// allocating whatever it asks for
+const MAX_FRAME_LENGTH: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum BatchFormat {
+    #[default]
+    Ndjson,
+    LengthPrefixed,
+}
+
+impl FromStr for BatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" => Ok(BatchFormat::Ndjson),
+            "length_prefixed" => Ok(BatchFormat::LengthPrefixed),
+            _ => Err(format!(
+                "'{}' is not a valid batch format, must be one of ndjson or length_prefixed",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    path: Option<String>,
+    contents: String,
+}
+
+#[derive(Serialize, Default)]
+struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    repairs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ResponseError {
+    message: String,
+    /// JSON pointer to where the notebook does not match the nbformat schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pointer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cell: Option<usize>,
+}
+
+impl From<stripoutlib::StripError> for ResponseError {
+    fn from(error: stripoutlib::StripError) -> Self {
+        let violation = error.violations.first();
+        ResponseError {
+            pointer: violation.map(|v| v.pointer.clone()),
+            cell: violation.and_then(|v| v.cell),
+            message: error.to_string(),
+        }
+    }
+}
+
+fn respond(frame: &[u8], options: &mut stripoutlib::StripOptions) -> Response {
+    let request: Request = match serde_json::from_slice(frame) {
+        Ok(request) => request,
+        Err(e) => {
+            return Response {
+                error: Some(ResponseError {
+                    message: format!("Batch request is not valid: {}", e),
+                    pointer: None,
+                    cell: None,
+                }),
+                ..Default::default()
+            }
+        }
+    };
+    options.zeppelin = request
+        .path
+        .as_deref()
+        .is_some_and(|path| crate::is_zeppelin_path(Path::new(path)));
+
+    match crate::strip_contents(&request.contents, options) {
+        Ok((cleaned_contents, repairs)) => Response {
+            path: request.path,
+            changed: Some(cleaned_contents != request.contents),
+            contents: Some(cleaned_contents),
+            error: None,
+            repairs,
+        },
+        Err(e) => Response {
+            path: request.path,
+            error: Some(e.into()),
+            ..Default::default()
+        },
+    }
+}
+
+fn write_response(
+    out: &mut impl Write,
+    response: &Response,
+    format: BatchFormat,
+) -> Result<(), String> {
+    let bytes = serde_json::to_vec(response)
+        .map_err(|e| format!("Could not serialize batch response: {:?}", e))?;
+    match format {
+        BatchFormat::Ndjson => out.write_all(&bytes).and_then(|_| out.write_all(b"\n")),
+        BatchFormat::LengthPrefixed => out
+            .write_all(format!("{}\n", bytes.len()).as_bytes())
+            .and_then(|_| out.write_all(&bytes)),
+    }
+    // Flushed after every frame so that callers can wait for each response
+    .and_then(|_| out.flush())
+    .map_err(|e| format!("Could not write to STDOUT due to {:?}", e))
+}
+
+/// Read the next request frame, or None at the end of STDIN
+fn read_frame(input: &mut impl BufRead, format: BatchFormat) -> Result<Option<Vec<u8>>, String> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = input
+            .read_until(b'\n', &mut line)
+            .map_err(|e| format!("Could not read STDIN: {:?}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        // Blank lines between frames are allowed
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            break;
+        }
+    }
+    match format {
+        BatchFormat::Ndjson => Ok(Some(line)),
+        BatchFormat::LengthPrefixed => {
+            let header = String::from_utf8_lossy(&line);
+            let length: u64 = header.trim().parse().map_err(|_| {
+                format!(
+                    "Batch frame header '{}' is not a length in bytes. Exiting...",
+                    header.trim()
+                )
+            })?;
+            if length > MAX_FRAME_LENGTH {
+                return Err(format!(
+                    "Batch frame of {} bytes is larger than the maximum of {} bytes. Exiting...",
+                    length, MAX_FRAME_LENGTH
+                ));
+            }
+            let mut frame = Vec::new();
+            input
+                .take(length)
+                .read_to_end(&mut frame)
+                .map_err(|e| format!("Could not read STDIN: {:?}", e))?;
+            if (frame.len() as u64) < length {
+                return Err(format!(
+                    "STDIN ended {} bytes into a batch frame of {} bytes. Exiting...",
+                    frame.len(),
+                    length
+                ));
+            }
+            Ok(Some(frame))
+        }
+    }
+}
+
+/// Strip every notebook sent on STDIN, writing a response for each to STDOUT
+pub fn run(format: BatchFormat, options: &mut stripoutlib::StripOptions) -> Result<(), String> {
+    let mut input = io::stdin().lock();
+    let mut out = io::stdout().lock();
+    while let Some(frame) = read_frame(&mut input, format)? {
+        let response = respond(&frame, options);
+        if let Some(error) = &response.error {
+            log::debug!("Batch request for {:?} failed: {}", response.path, error.message);
+        }
+        write_response(&mut out, &response, format)?;
+    }
+    Ok(())
+}
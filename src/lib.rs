@@ -2,43 +2,75 @@ mod stripoutlib;
 
 #[cfg(feature = "extension-module")]
 mod python {
-    use pyo3::exceptions::PyRuntimeError;
+    use pyo3::exceptions::{PyRuntimeError, PyUserWarning};
     use pyo3::prelude::*;
+    use std::ffi::CString;
 
     use super::stripoutlib;
 
-    /// Strips output from a notebook (string) and returns back a notebook (string)
+    /// Read settings from a mapping with the same keys as in .git-nbconfig.yaml, through
+    /// JSON so that they are parsed exactly like the config
+    fn extract_settings(
+        py: Python<'_>,
+        settings: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<stripoutlib::StripConfig> {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return Ok(stripoutlib::StripConfig::default()),
+        };
+        let json: String = py
+            .import("json")?
+            .call_method1("dumps", (settings,))?
+            .extract()?;
+        serde_json::from_str(&json)
+            .map_err(|e| PyRuntimeError::new_err(format!("settings are not valid: {}", e)))
+    }
+
+    /// Strips output from a notebook (string) and returns back a notebook (string).
+    /// `settings` takes any other setting, with the same keys as in .git-nbconfig.yaml, e.g.
+    /// `{"keep_keys": ["metadata.kernelspec"], "indent": 2}`.
     #[pyfunction]
-    #[pyo3(signature = (contents, keep_output, keep_count, extra_keys, drop_empty_cells, strip_regex = None))]
+    #[pyo3(signature = (contents, keep_output, keep_count, extra_keys, drop_empty_cells, strip_regex = None, settings = None, zeppelin = false))]
+    #[allow(clippy::too_many_arguments)]
     fn stripout(
+        py: Python<'_>,
         contents: String,
         keep_output: bool,
         keep_count: bool,
         extra_keys: Vec<String>,
         drop_empty_cells: bool,
         strip_regex: Option<String>,
+        settings: Option<&Bound<'_, PyAny>>,
+        zeppelin: bool,
     ) -> PyResult<String> {
         // If rust ever comes up with a PyObject to serde we should accept a
         // notebook object instead. This is cheap and mostly used for testing
-        let mut nb: serde_json::Value = serde_json::from_str(&contents).map_err(|e| {
-            PyRuntimeError::new_err(format!("JSON was not well-formatted: {:?}", e))
-        })?;
+        let mut nb = stripoutlib::parse(&contents).map_err(PyRuntimeError::new_err)?;
 
-        stripoutlib::strip_output(
-            &mut nb,
+        // The extra keys passed in are used as they are, unless `use_default_extra_keys`
+        let mut options = stripoutlib::StripOptions {
             keep_output,
             keep_count,
-            &extra_keys,
+            extra_keys,
             drop_empty_cells,
-            strip_regex.as_deref(),
-        )
-        .map_err(PyRuntimeError::new_err)?;
-
-        let cleaned_contents = serde_json::to_string_pretty(&nb).map_err(|e| {
-            PyRuntimeError::new_err(format!("JSON output was not well-formatted: {:?}", e))
-        })?;
+            strip_regex,
+            zeppelin,
+            ..Default::default()
+        };
+        options
+            .update(extract_settings(py, settings)?)
+            .map_err(PyRuntimeError::new_err)?;
+        let overridden =
+            stripoutlib::notebook_options(&nb, &options).map_err(PyRuntimeError::new_err)?;
+        let options = overridden.as_ref().unwrap_or(&options);
+        let repairs = stripoutlib::strip_output(&mut nb, options)
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        for repair in repairs {
+            let message = CString::new(format!("Repaired {}", repair))?;
+            PyErr::warn(py, &py.get_type::<PyUserWarning>(), &message, 1)?;
+        }
 
-        Ok(cleaned_contents)
+        stripoutlib::serialize(&nb, &contents, options).map_err(PyRuntimeError::new_err)
     }
 
     /// nbstripout, but in rust!
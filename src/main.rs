@@ -4,32 +4,19 @@
  * https://github.com/kynan/nbstripout_fast/blob/master/nbstripout_fast/_utils.py
  * in order to make it easier to keep feature parity.
  *
- * Note: Not all features were ported (e.g. zeppelin notebooks).
+ * Zeppelin notes are detected from their structure, or from the `.zpln` extension.
  */
 use clap::Parser;
 use serde::Deserialize;
-use serde::Serialize;
 use std::env;
 use std::fs;
 use std::io;
-use std::io::BufRead;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
+mod batch;
 mod stripoutlib;
 
-// Match: https://github.com/kynan/nbstripout_fast/blob/master/nbstripout_fast/_nbstripout_fast.py#L431
-const DEFAULT_EXTRA_KEYS: [&str; 9] = [
-    "metadata.signature",
-    "metadata.vscode",
-    "metadata.widgets",
-    "cell.metadata.collapsed",
-    "cell.metadata.ExecuteTime",
-    "cell.metadata.execution",
-    "cell.metadata.heading_collapsed",
-    "cell.metadata.hidden",
-    "cell.metadata.scrolled",
-];
-
 #[derive(Parser)]
 /// Strip output from Jupyter notebooks (modifies the files in place by default).
 ///
@@ -45,6 +32,10 @@ const DEFAULT_EXTRA_KEYS: [&str; 9] = [
 ///
 /// cat my-notebook.ipynb | nbstripout_fast-fast > OUT.ipynb
 ///
+/// As a git filter: nbstripout_fast-fast --stdin-file-path %f
+///
+/// For many notebooks in one process: nbstripout_fast-fast --batch
+///
 #[clap(author, version, about)]
 struct Cli {
     #[clap(long, action)]
@@ -59,18 +50,91 @@ struct Cli {
     /// Remove cells where `source` is empty or contains only whitespace
     drop_empty_cells: bool,
 
+    #[clap(long, action)]
+    /// Drop empty outputs and merge consecutive outputs to the same stream
+    normalize_outputs: bool,
+
+    #[clap(long, action)]
+    /// Strip papermill metadata and drop the cell of parameters injected by papermill
+    papermill: bool,
+
+    #[clap(long, action)]
+    /// With --papermill, also strip the outputs of the cell tagged `parameters`
+    papermill_clear_parameters: bool,
+
+    #[clap(long, action)]
+    /// Strip the outputs of cells whose outputs are collapsed
+    strip_hidden_outputs: bool,
+
+    #[clap(long, action)]
+    /// Upgrade nbformat 3 notebooks to nbformat 4
+    upgrade_v3: bool,
+
+    #[clap(long, action)]
+    /// How to indent the notebooks written out: auto (like the input), a number of spaces, tab
+    /// or compact
+    indent: Option<stripoutlib::Indent>,
+
+    #[clap(long, action)]
+    /// Write notebooks like nbformat (and so Jupyter) does: with sorted keys, one space of
+    /// indentation, and multiline strings as lists of lines
+    nbformat_compatible: bool,
+
+    #[clap(long, action)]
+    /// Line endings to write: lf, crlf or preserve (like the input)
+    eol: Option<stripoutlib::Eol>,
+
+    #[clap(long, action)]
+    /// Fail on notebooks that do not match the nbformat 4 schema
+    validate: bool,
+
+    #[clap(long, action)]
+    /// Fail if a stripped notebook does not match the nbformat 4 schema
+    validate_output: bool,
+
+    #[clap(long, action)]
+    /// Repair common malformations instead of failing on them, e.g. code cells without outputs,
+    /// markdown cells with outputs, null metadata or sources that are not strings. Repairs are
+    /// reported on STDERR
+    repair: bool,
+
     #[clap(short, long, action)]
     /// Prints stripped files to STDOUT
     textconv: bool,
 
     #[clap(short, long, action)]
-    /// Space separated list of extra keys to strip
+    /// Space separated list of extra keys to strip, e.g. `metadata.foo`, `cell.metadata.bar`,
+    /// `code.metadata.bar` or `output.baz`. A `*` segment matches any single key and `**` matches any number of
+    /// keys, e.g. `cell.metadata.jupyter.*`
     extra_keys: Option<String>,
 
     #[clap(short, long, action)]
-    /// Space separated list of extra keys NOT to strip (even if in defaults or extra_keys)
+    /// Space separated list of extra keys NOT to strip (even if in defaults or extra_keys).
+    /// Accepts the same patterns as --extra-keys, and may name keys inside a stripped key
     keep_keys: Option<String>,
 
+    #[clap(long = "preset", value_name = "PRESET", action = clap::ArgAction::Append)]
+    /// Apply the settings for a notebook front-end: colab, databricks, jupyterlab, kaggle or
+    /// vscode. May be given more than once
+    presets: Vec<String>,
+
+    #[clap(long, action)]
+    /// Do not strip the default extra keys
+    no_default_extra_keys: bool,
+
+    #[clap(long, action)]
+    /// Space separated list of output types to strip, e.g. `error` or `stream:stderr`
+    strip_output_types: Option<String>,
+
+    #[clap(long, action)]
+    /// Space separated list of output types to keep, even without --keep-output, e.g. `stream:stdout`
+    keep_output_types: Option<String>,
+
+    #[clap(long, action)]
+    /// What to do with outputs of types not in the notebook format: keep (like other outputs),
+    /// strip or error
+    unknown_output_types: Option<stripoutlib::UnknownOutputTypes>,
+
     #[clap(short, long, action)]
     /// Ignore settings from .git-nbconfig.yaml
     ignore_git_nb_config: bool,
@@ -82,29 +146,66 @@ struct Cli {
     /// https://docs.rs/regex/latest/regex/ for more information.
     strip_regex: Option<String>,
 
+    #[clap(long, action)]
+    /// Space separated list of notebook metadata keys to keep; all other notebook metadata is
+    /// stripped, e.g. `kernelspec language_info.name`
+    keep_only_metadata: Option<String>,
+
+    #[clap(long, action)]
+    /// Space separated list of cell metadata keys to keep; all other cell metadata is
+    /// stripped, e.g. `tags slideshow`
+    keep_only_cell_metadata: Option<String>,
+
+    #[clap(long, action)]
+    /// How to handle ipywidgets outputs: `keep` them as they are, `drop_orphans` to drop those
+    /// whose state is stripped, `prune_state` to only keep the widget state used by kept outputs,
+    /// or `text_fallback` to replace them with their text/plain output
+    widget_policy: Option<stripoutlib::WidgetPolicy>,
+
+    #[clap(long, action)]
+    /// Write cell sources and textual outputs as a list of `lines` (like nbformat), as a single
+    /// `string`, or `preserve` whichever each notebook uses
+    multiline_format: Option<stripoutlib::MultilineFormat>,
+
+    #[clap(long, action)]
+    /// Space separated list of cell types (`code`, `markdown`, `raw`) to remove trailing
+    /// whitespace and blank lines from. Tabs in code cell indentation are replaced with spaces
+    normalize_source: Option<String>,
+
+    #[clap(long, parse(from_os_str), value_name = "PATH")]
+    /// Path of the notebook read from STDIN, e.g. git's `%f`. Used to find .git-nbconfig.yaml,
+    /// detect Zeppelin notes and name the notebook in errors
+    stdin_file_path: Option<PathBuf>,
+
+    #[clap(long, action, conflicts_with_all = &["files", "textconv", "stdin-file-path"])]
+    /// Strip the notebooks sent as requests on STDIN, writing a response for each to STDOUT.
+    /// See --batch-format
+    batch: bool,
+
+    #[clap(long, action, value_name = "FORMAT", requires = "batch")]
+    /// Framing of --batch requests and responses: `ndjson` (one JSON object per line, the
+    /// default) or `length_prefixed` (a line with the length in bytes, then the JSON object).
+    /// Requests are `{"path": ..., "contents": ...}` and responses
+    /// `{"path": ..., "contents": ..., "changed": ...}` or `{"path": ..., "error": ...}`
+    batch_format: Option<batch::BatchFormat>,
+
     #[clap(parse(from_os_str))]
     /// Files to strip output from
     files: Vec<PathBuf>,
 }
 
-#[derive(Deserialize, Debug)]
-struct NBConfigNBStripOutFastConfig {
-    keep_output: Option<bool>,
-    keep_count: Option<bool>,
-    drop_empty_cells: Option<bool>,
-    extra_keys: Option<Vec<String>>,
-    keep_keys: Option<Vec<String>>,
-    strip_regex: Option<String>,
-}
-
 #[derive(Deserialize, Debug)]
 struct NBConfig {
-    nbstripout_fast: Option<NBConfigNBStripOutFastConfig>,
+    nbstripout_fast: Option<stripoutlib::StripConfig>,
 }
 
-// TODO: Maybe this should load relative to the file
-fn find_nbconfig() -> Result<Option<NBConfig>, String> {
+// TODO: Maybe this should load relative to each file, not just the one on stdin
+fn find_nbconfig(stdin_file_path: Option<&Path>) -> Result<Option<NBConfig>, String> {
     let mut dir = env::current_dir().map_err(|e| format!("Unable to read current dir {:?}", e))?;
+    // Relative paths (like git's %f) are relative to the current dir
+    if let Some(parent) = stdin_file_path.and_then(|path| path.parent()) {
+        dir = dir.join(parent);
+    }
     // Find .git. We don't want too many dependencies, so we do this a bit hacky
     loop {
         let git_dir = dir.as_path().join(".git");
@@ -136,144 +237,137 @@ fn find_nbconfig() -> Result<Option<NBConfig>, String> {
     }
 }
 
+/// The stripped notebook, and the repairs made to it
+fn strip_contents(
+    contents: &str,
+    options: &stripoutlib::StripOptions,
+) -> Result<(String, Vec<String>), stripoutlib::StripError> {
+    let mut nb = stripoutlib::parse(contents)?;
+    let overridden = stripoutlib::notebook_options(&nb, options)?;
+    let options = overridden.as_ref().unwrap_or(options);
+
+    let repairs = stripoutlib::strip_output(&mut nb, options)?;
+
+    Ok((stripoutlib::serialize(&nb, contents, options)?, repairs))
+}
+
+/// Strip a notebook and write it to `output_file`, if it changed, or to STDOUT. STDOUT always
+/// gets the whole notebook, byte for byte, as git filters replace the file with it.
 fn process_file(
     contents: &String,
-    keep_output: bool,
-    keep_count: bool,
-    extra_keys: &Vec<String>,
-    drop_empty_cells: bool,
-    strip_regex: Option<&str>,
+    options: &stripoutlib::StripOptions,
+    name: &str,
     output_file: Option<PathBuf>,
 ) -> Result<(), String> {
-    let mut nb: serde_json::Value = serde_json::from_str(contents)
-        .map_err(|e| format!("JSON was not well-formatted: {:?}", e))?;
-
-    stripoutlib::strip_output(
-        &mut nb,
-        keep_output,
-        keep_count,
-        extra_keys,
-        drop_empty_cells,
-        strip_regex,
-    )?;
-
-    // Format with 1 space to match nbformat
-    // https://stackoverflow.com/questions/42722169/generate-pretty-indented-json-with-serde
-    let buf = Vec::new();
-    let formatter = serde_json::ser::PrettyFormatter::with_indent(b" ");
-    let mut ser = serde_json::Serializer::with_formatter(buf, formatter);
-    nb.serialize(&mut ser).map_err(|e| {
-        format!(
-            "Unable to serialize notebook. Likely an internal error: {:?}",
-            e
-        )
-    })?;
-    let mut cleaned_contents = String::from_utf8(ser.into_inner()).map_err(|e| format!("{:?}", e))?;
-
-    // Check if the original content ended with a newline and the cleaned content doesn't
-    if contents.ends_with('\n') && !cleaned_contents.ends_with('\n') {
-        cleaned_contents.push('\n'); // Append a newline if necessary
+    let (cleaned_contents, repairs) =
+        strip_contents(contents, options).map_err(|e| format!("{}: {}", name, e))?;
+    for repair in repairs {
+        eprintln!("{}: repaired {}", name, repair);
     }
 
-    if cleaned_contents != *contents {
-        if let Some(file) = output_file {
+    match output_file {
+        Some(file) if cleaned_contents != *contents => {
             fs::write(&file, cleaned_contents)
                 .map_err(|e| format!("Could not write to {:?} due to {:?}", file, e))?;
-        } else {
-            println!("{}", cleaned_contents);
         }
-    } else {
-        log::debug!("Content unchanged. File not modified.");
+        Some(_) => log::debug!("Content unchanged. File not modified."),
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout
+                .write_all(cleaned_contents.as_bytes())
+                .and_then(|_| stdout.flush())
+                .map_err(|e| format!("Could not write to STDOUT due to {:?}", e))?;
+        }
     }
 
     Ok(())
 }
 
+fn is_zeppelin_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "zpln")
+}
+
 fn main() -> Result<(), String> {
     env_logger::init();
-    let config = find_nbconfig()?;
     let args = Cli::parse();
+    let config = match args.ignore_git_nb_config {
+        false => find_nbconfig(args.stdin_file_path.as_deref())?,
+        true => None,
+    };
 
-    let mut keep_output = false;
-    let mut keep_count = false;
-    let mut drop_empty_cells = false;
-    let mut strip_regex: Option<String> = args.strip_regex;
-
-    let mut extra_keys: Vec<String> = vec![];
-    for key in DEFAULT_EXTRA_KEYS {
-        extra_keys.push(key.to_string());
-    }
+    let mut options = stripoutlib::StripOptions {
+        default_extra_keys: true,
+        ..Default::default()
+    };
 
     // Process config first so that the CLI overrides this
     if let Some(config_yaml) = config {
         if let Some(nbstripout_fast) = config_yaml.nbstripout_fast {
-            keep_output = nbstripout_fast.keep_output.unwrap_or(keep_output);
-            keep_count = nbstripout_fast.keep_count.unwrap_or(keep_count);
-            drop_empty_cells = nbstripout_fast.drop_empty_cells.unwrap_or(drop_empty_cells);
-            strip_regex = strip_regex.or(nbstripout_fast.strip_regex);
-            if let Some(config_extra_keys) = nbstripout_fast.extra_keys {
-                for key in config_extra_keys {
-                    extra_keys.push(key.to_string());
-                }
-            }
-            if let Some(config_keep_keys) = nbstripout_fast.keep_keys {
-                for key in config_keep_keys {
-                    // Remove all occurrences
-                    extra_keys.retain(|x| x != &key);
-                }
-            }
+            options.update(nbstripout_fast)?;
         }
     }
 
-    if let Some(extra_keys_str) = args.extra_keys {
-        let cli_extra_keys: Vec<String> = extra_keys_str
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect();
-        for key in cli_extra_keys {
-            extra_keys.push(key);
-        }
-    }
-    if let Some(cli_keep_keys_str) = args.keep_keys {
-        let cli_keep_keys: Vec<String> = cli_keep_keys_str
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect();
-        for key in cli_keep_keys {
-            // Remove all occurances
-            extra_keys.retain(|x| x != &key);
-        }
-    }
-    if args.keep_output {
-        keep_output = true;
-    }
-    if args.keep_count {
-        keep_count = true;
-    }
-    if args.drop_empty_cells {
-        drop_empty_cells = true;
+    // The CLI overrides the config, with the same settings
+    let words = |s: Option<String>| -> Option<Vec<String>> {
+        s.map(|s| s.split_whitespace().map(|s| s.to_string()).collect())
+    };
+    let flag = |set: bool| set.then_some(true);
+    let extra_keys = words(args.extra_keys);
+    for key in extra_keys.iter().flatten() {
+        // Keys requested on the CLI win over keep_keys from the config
+        options.keep_keys.retain(|x| x != key);
     }
+    options.update(stripoutlib::StripConfig {
+        presets: Some(args.presets),
+        use_default_extra_keys: args.no_default_extra_keys.then_some(false),
+        keep_output: flag(args.keep_output),
+        keep_count: flag(args.keep_count),
+        drop_empty_cells: flag(args.drop_empty_cells),
+        normalize_outputs: flag(args.normalize_outputs),
+        papermill: flag(args.papermill),
+        papermill_clear_parameters: flag(args.papermill_clear_parameters),
+        strip_hidden_outputs: flag(args.strip_hidden_outputs),
+        upgrade_v3: flag(args.upgrade_v3),
+        indent: args.indent,
+        nbformat_compatible: flag(args.nbformat_compatible),
+        eol: args.eol,
+        validate: flag(args.validate),
+        validate_output: flag(args.validate_output),
+        repair: flag(args.repair),
+        extra_keys,
+        keep_keys: words(args.keep_keys),
+        strip_output_types: words(args.strip_output_types),
+        keep_output_types: words(args.keep_output_types),
+        unknown_output_types: args.unknown_output_types,
+        strip_regex: args.strip_regex,
+        keep_only_metadata: words(args.keep_only_metadata),
+        keep_only_cell_metadata: words(args.keep_only_cell_metadata),
+        widget_policy: args.widget_policy,
+        multiline_format: args.multiline_format,
+        normalize_source: words(args.normalize_source),
+        ..Default::default()
+    })?;
+
+    log::debug!("Using {:?}", options);
+    if args.batch {
+        log::debug!("Processing batch requests from stdin");
+        batch::run(args.batch_format.unwrap_or_default(), &mut options)?;
+    } else if args.files.is_empty() {
+        log::debug!("Processing stdin as {:?}", args.stdin_file_path);
+        let name = match &args.stdin_file_path {
+            Some(path) => format!("{:?}", path),
+            None => "STDIN".to_string(),
+        };
+        let mut bytes = Vec::new();
+        io::stdin()
+            .lock()
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("Could not read {}: {:?}", name, e.to_string()))?;
+        let contents = String::from_utf8(bytes)
+            .map_err(|e| format!("Could not load {}: {:?}", name, e.to_string()))?;
 
-    log::debug!(
-        "Using keep_count: {} keep_output: {} drop_empty_cells: {} strip_regex: {:?} extra_keys: {:?} ",
-        keep_count,
-        keep_output,
-        drop_empty_cells,
-        strip_regex,
-        extra_keys
-    );
-    if args.files.is_empty() {
-        log::debug!("Processing stdin");
-        let contents: String = io::stdin().lock().lines().map(|ln| ln.unwrap()).collect();
-        process_file(
-            &contents,
-            keep_output,
-            keep_count,
-            &extra_keys,
-            drop_empty_cells,
-            strip_regex.as_deref(),
-            None,
-        )?;
+        options.zeppelin = args.stdin_file_path.as_deref().is_some_and(is_zeppelin_path);
+        process_file(&contents, &options, &name, None)?;
     } else {
         for file in args.files {
             // Much faster than using from_reader for some reason - https://github.com/serde-rs/json/issues/160
@@ -281,20 +375,14 @@ fn main() -> Result<(), String> {
             let contents = fs::read_to_string(&file)
                 .map_err(|e| format!("Could not load {:?}: {:?}", file, e.to_string()))?;
 
+            options.zeppelin = is_zeppelin_path(&file);
+            let name = format!("{:?}", file);
             let output_file = match args.textconv {
                 false => Some(file),
                 true => None,
             };
 
-            process_file(
-                &contents,
-                keep_output,
-                keep_count,
-                &extra_keys,
-                drop_empty_cells,
-                strip_regex.as_deref(),
-                output_file,
-            )?;
+            process_file(&contents, &options, &name, output_file)?;
         }
     }
 
@@ -1,43 +1,118 @@
 // This code is nearly a 1:1 mapping of https://github.com/kynan/nbstripout/blob/master/nbstripout/_utils.py
 use regex::Regex;
+use serde::Deserialize;
 use serde_json::json;
-use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+
+mod format;
+mod kernelspec;
+mod keypath;
+mod multiline;
+mod outputs;
+mod presets;
+mod repair;
+mod schema;
+mod source;
+mod v3;
+mod widgets;
+mod zeppelin;
+
+use keypath::KeyPattern;
+use outputs::OutputTypes;
+#[cfg_attr(not(feature = "extension-module"), allow(unused))]
+pub use format::{parse, serialize, Eol, Indent};
+pub use kernelspec::KernelspecOptions;
+pub use multiline::MultilineFormat;
+pub use outputs::UnknownOutputTypes;
+pub use schema::ValidationError;
+pub use widgets::WidgetPolicy;
+
+// Match: https://github.com/kynan/nbstripout_fast/blob/master/nbstripout_fast/_nbstripout_fast.py#L431
+pub const DEFAULT_EXTRA_KEYS: [&str; 9] = [
+    "metadata.signature",
+    "metadata.vscode",
+    "metadata.widgets",
+    "cell.metadata.collapsed",
+    "cell.metadata.ExecuteTime",
+    "cell.metadata.execution",
+    "cell.metadata.heading_collapsed",
+    "cell.metadata.hidden",
+    "cell.metadata.scrolled",
+];
 
 type JSONMap = serde_json::Map<String, serde_json::Value>;
 
-// Given a key 'a.b.c' find and remove it
-// e.g. "a.b": {"c": "d"} OR "a": {"b": {"c": "d"}}
-fn pop_recursive(d: &mut serde_json::Value, key: &str) {
-    let obj = match d.as_object_mut() {
-        Some(x) => x,
-        None => return,
-    };
-    if obj.contains_key(key) {
-        obj.remove_entry(key);
-        return;
-    }
-    if !key.contains(".") {
-        return;
+/// Names of the tags that control a single cell. Each may also be set as a boolean
+/// key in the cell metadata.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CellTags {
+    /// Keep the outputs of the cell
+    pub keep_output: String,
+    /// Strip the outputs of the cell, even if outputs are kept by default
+    pub strip_output: String,
+    /// Keep the execution count of the cell
+    pub keep_count: String,
+}
+
+impl Default for CellTags {
+    fn default() -> Self {
+        CellTags {
+            keep_output: "keep_output".to_string(),
+            strip_output: "strip_output".to_string(),
+            keep_count: "keep_count".to_string(),
+        }
     }
+}
+
+/// Does the cell metadata have `name` in its tags?
+fn metadata_has_tag(metadata: &JSONMap, name: &str) -> bool {
+    metadata
+        .get("tags")
+        .and_then(|tags| tags.as_array())
+        .is_some_and(|tags| tags.iter().any(|tag| tag.as_str() == Some(name)))
+}
 
-    let (key_head, key_tail) = key.split_once(".").unwrap();
-    if obj.contains_key(key_head) {
-        pop_recursive(obj.get_mut(key_head).unwrap(), key_tail)
+/// Is `name` set for the cell, either as a tag or as `true` in its metadata?
+fn has_directive(cell: &JSONMap, name: &str) -> bool {
+    match cell.get("metadata").and_then(|value| value.as_object()) {
+        Some(metadata) => {
+            metadata_has_tag(metadata, name) || metadata.get(name) == Some(&json!(true))
+        }
+        None => false,
     }
 }
 
 /// Should we keep the output of a given cell?
 ///
 /// If the regex is specified, it will be matched against the cell's
-/// "text/plain" or "text" output; if a match is found, the cell's output will
-/// be discarded unconditionally. If no match is found, the cell output will
-/// only be kept if `keep_output` is in the cell metadata or tags, or if
-/// the default is to keep it.
+/// "text/plain" or "text" output (or the error name and value); if a match is found, the cell's output will
+/// be discarded unconditionally, as are outputs of the `strip_types`. All
+/// outputs are discarded if the `strip_output` tag is in the cell metadata or
+/// tags (or, if `strip_hidden` is set, the outputs are collapsed). Otherwise
+/// an output is kept if it is one of the `keep_types`, if the `keep_output`
+/// tag is in the cell metadata or tags, or if the default is to keep it.
 ///
 /// * `cell`: Contents of a cell
 /// * `default`: Whether to keep cell output or not by default
 /// * `strip_regex`: Regex to use to determine whether output should be stripped
-fn determine_keep_output(cell: &JSONMap, default: bool, strip_regex: Option<&Regex>) -> Result<Vec<bool>, String> {
+/// * `strip_types`: Output types to strip
+/// * `keep_types`: Output types to keep
+/// * `unknown_types`: What to do with outputs of types not in the notebook format
+/// * `tags`: Names of the tags controlling the cell
+/// * `strip_hidden`: Whether collapsed outputs should be stripped
+#[allow(clippy::too_many_arguments)]
+fn determine_keep_output(
+    cell: &JSONMap,
+    default: bool,
+    strip_regex: Option<&Regex>,
+    strip_types: &OutputTypes,
+    keep_types: &OutputTypes,
+    unknown_types: UnknownOutputTypes,
+    tags: &CellTags,
+    strip_hidden: bool,
+) -> Result<Vec<bool>, String> {
 
     // Generate a vector with the same length as cell["outputs"],
     // filled with the given value
@@ -50,60 +125,82 @@ fn determine_keep_output(cell: &JSONMap, default: bool, strip_regex: Option<&Reg
 
     // If there's a metadata key, retrieve the JSON object. Otherwise exit out, and follow
     // the default clear behavior for all cell outputs
-    let metadata = match cell.get("metadata").and_then(|value| value.as_object()) {
-        Some(obj) => obj,
-        None => return make_filled_output(default)
-    };
+    let no_metadata = JSONMap::new();
+    let metadata = cell
+        .get("metadata")
+        .and_then(|value| value.as_object())
+        .unwrap_or(&no_metadata);
 
-    let has_keep_output_metadata = metadata.contains_key("keep_output");
+    let has_keep_output_metadata = metadata.contains_key(&tags.keep_output);
     let keep_output_metadata =
-        has_keep_output_metadata && metadata["keep_output"].as_bool().unwrap_or(false);
+        has_keep_output_metadata && metadata[&tags.keep_output].as_bool().unwrap_or(false);
 
-    let has_keep_output_tag = metadata
-        .get("tags")
-        .unwrap_or(&json!([]))
-        .as_array()
-        .unwrap_or(&vec![])
-        .contains(&serde_json::Value::String("keep_output".to_string()));
+    let has_keep_output_tag = metadata_has_tag(metadata, &tags.keep_output);
 
     if has_keep_output_metadata && has_keep_output_tag && !keep_output_metadata {
-        return Err(String::from(
-            "cell metadata contradicts tags: `keep_output` is false, but `keep_output` in tags",
+        return Err(format!(
+            "cell metadata contradicts tags: `{0}` is false, but `{0}` in tags",
+            tags.keep_output
+        ));
+    }
+
+    let hidden = metadata
+        .get("jupyter")
+        .and_then(|jupyter| jupyter.get("outputs_hidden"))
+        == Some(&json!(true));
+    let keep_requested = has_keep_output_tag || keep_output_metadata;
+    let strip_requested = has_directive(cell, &tags.strip_output);
+    if strip_requested && keep_requested {
+        return Err(format!(
+            "cell metadata contradicts itself: both `{}` and `{}` are set",
+            tags.keep_output, tags.strip_output
         ));
     }
+    // Collapsed outputs are only stripped if the cell does not ask to keep them
+    if strip_requested || (strip_hidden && hidden && !keep_requested) {
+        return make_filled_output(false);
+    }
+
+    let cell_keep = has_keep_output_tag || has_keep_output_metadata || default;
+    let outputs = cell
+        .get("outputs")
+        .and_then(|value| value.as_array())
+        .ok_or("Could not get cell outputs.")?;
 
-    match strip_regex {
-        Some(reg) => {
-            let mut result = Vec::new();
-            let outputs = cell
-                .get("outputs")
-                .and_then(|value| value.as_array())
-                .ok_or("Could not get cell outputs.")?;
-
-            for output in outputs {
-                let obj = output.as_object().ok_or("Cell output is not a JSON object; notebook is malformed.")?;
-                if output_matches_regex(obj, reg).unwrap_or(false) {
-                    // If there's a regex match, that takes precedence over any other
-                    // options
+    let mut result = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        // A regex match or a stripped output type takes precedence over any other options
+        if outputs::known_type(output).is_none() {
+            match unknown_types {
+                UnknownOutputTypes::Keep => {}
+                UnknownOutputTypes::Strip => {
                     result.push(false);
-                } else if has_keep_output_tag || has_keep_output_metadata {
-                    result.push(true);
-                } else {
-                    result.push(default);
+                    continue;
+                }
+                UnknownOutputTypes::Error => {
+                    return Err(format!(
+                        "Cell output has an unknown output type {}. Exiting...",
+                        output.get("output_type").unwrap_or(&serde_json::Value::Null)
+                    ))
                 }
-            }
-            Ok(result)
-        },
-        None => {
-            // Regex not provided; if metadata or tags request we keep it, then keep it; otherwise
-            // revert to `default` behavior
-            if has_keep_output_metadata || has_keep_output_tag {
-                make_filled_output(true)
-            } else {
-                make_filled_output(default)
             }
         }
+        let regex_match = match strip_regex {
+            // A malformed output can not match, and is left to the other settings
+            Some(reg) => output_matches_regex(output, reg).unwrap_or_else(|e| {
+                log::debug!("Not matching the strip regex: {}", e);
+                false
+            }),
+            None => false,
+        };
+        let keep = if regex_match || strip_types.matches(output) {
+            false
+        } else {
+            keep_types.matches(output) || cell_keep
+        };
+        result.push(keep);
     }
+    Ok(result)
 }
 
 /// If a regex is specified, return true if it matches the output and false otherwise.
@@ -114,119 +211,472 @@ fn determine_keep_output(cell: &JSONMap, default: bool, strip_regex: Option<&Reg
 /// * `output`: Cell output map with text content to be matched against the regex
 /// * `strip_regex`: Regex to match the output against
 fn output_matches_regex(
-    output: &JSONMap,
+    output_value: &serde_json::Value,
     strip_regex: &Regex
 ) -> Result<bool, String> {
+    let output = output_value.as_object().ok_or("Cell output is not a JSON object; notebook is malformed.")?;
     let output_type = output
         .get("output_type")
         .ok_or("Cell output does not contain an output type; notebook data is malformed.")?
         .as_str()
         .ok_or("Cell output type is not a string; notebook data is malformed.")?;
 
-    let data = match output_type {
+    let text = match v3::v4_output_type(output_type) {
         "stream" => {
             output
                 .get("text")
                 .ok_or("Cell output of type 'stream' does not have 'text' key; notebook is malformed.")?
         },
-        "display_data" | "execute_result" => {
-            output
-                .get("data")
-                .ok_or(format!("Cell output of type '{}' does not have 'data' key; notebook is malformed.", output_type))?
-                .get("text/plain")
-                .ok_or(format!("Cell output of type '{}' does not have 'text/plain' key in its 'data' value; notebook is malformed.", output_type))?
+        "display_data" | "execute_result" | "update_display_data" => {
+            match v3::plain_text(output_value) {
+                Some(text) => text,
+                // e.g. only an image, which the regex can not match
+                None => return Ok(false),
+            }
         },
         "error" => {
-            return Ok(false)
+            // Match the last line of the traceback, e.g. `ZeroDivisionError: division by zero`
+            let field = |key: &str| output.get(key).and_then(|v| v.as_str()).unwrap_or("");
+            return Ok(strip_regex.is_match(&format!("{}: {}", field("ename"), field("evalue"))));
         }
-        _ => return Err("Malformed cell output type.".to_string())
+        // Left to the unknown output types policy
+        _ => return Ok(false)
     };
 
-    let joined = data
-        .as_array()
-        .ok_or("Could not get contents of a cell output.")?
-        .iter()
-        .map(|line| line.as_str().unwrap_or("").to_string())
-        .collect::<Vec<String>>()
-        .join("");
+    let joined = multiline::join(text).ok_or("Could not get contents of a cell output.")?;
 
     Ok(strip_regex.is_match(&joined))
 }
 
-// TODO: add custom errors instead of returning a string
+/// Does the cell have the given tag?
+fn has_tag(cell: &serde_json::Value, tag: &str) -> bool {
+    cell.get("metadata")
+        .and_then(|m| m.as_object())
+        .is_some_and(|metadata| metadata_has_tag(metadata, tag))
+}
+
+/// Settings for `strip_output`
+#[derive(Debug, Default, Clone)]
+pub struct StripOptions {
+    /// Keep cell outputs (unless they match `strip_regex`)
+    pub keep_output: bool,
+    /// Keep execution counts
+    pub keep_count: bool,
+    /// Keys to remove, e.g. `metadata.foo`, `cell.metadata.*` or `output:stream.name`
+    pub extra_keys: Vec<String>,
+    /// Remove the `DEFAULT_EXTRA_KEYS` too
+    pub default_extra_keys: bool,
+    /// Keys to keep even if they are matched by `extra_keys`
+    pub keep_keys: Vec<String>,
+    /// Remove cells with an empty or whitespace-only source
+    pub drop_empty_cells: bool,
+    /// Strip outputs matching this regex, even if they would otherwise be kept
+    pub strip_regex: Option<String>,
+    /// If set, only these keys are kept in the notebook metadata
+    pub keep_only_metadata: Option<Vec<String>>,
+    /// If set, only these keys are kept in each cell's metadata
+    pub keep_only_cell_metadata: Option<Vec<String>>,
+    /// How to handle ipywidgets outputs and state
+    pub widget_policy: WidgetPolicy,
+    /// Drop empty outputs and merge consecutive outputs to the same stream
+    pub normalize_outputs: bool,
+    /// Write sources and textual outputs as lists of lines or as single strings
+    pub multiline_format: MultilineFormat,
+    /// Cell types (`code`, `markdown` or `raw`) whose source should have trailing
+    /// whitespace and blank lines removed. Tabs in the indentation of code cells are
+    /// replaced with spaces too.
+    pub normalize_source: Vec<String>,
+    /// Pin or rename the kernelspec, and trim language_info
+    pub kernelspec: KernelspecOptions,
+    /// Strip papermill metadata and drop the cell of parameters injected by papermill
+    pub papermill: bool,
+    /// With `papermill`, also strip the outputs of the `parameters` cell
+    pub papermill_clear_parameters: bool,
+    /// Names of the tags that control a single cell
+    pub tags: CellTags,
+    /// Strip the outputs of cells whose outputs are collapsed
+    pub strip_hidden_outputs: bool,
+    /// Output types (e.g. `error` or `stream:stderr`) whose outputs are stripped
+    pub strip_output_types: Vec<String>,
+    /// Output types whose outputs are kept, even without `keep_output`
+    pub keep_output_types: Vec<String>,
+    /// What to do with outputs of types not in the notebook format
+    pub unknown_output_types: UnknownOutputTypes,
+    /// Upgrade nbformat 3 notebooks to nbformat 4
+    pub upgrade_v3: bool,
+    /// Treat the input as a Zeppelin note, even if it does not look like one
+    pub zeppelin: bool,
+    /// How to indent the notebook written out
+    pub indent: Indent,
+    /// Write the notebook like `nbformat.writes`, except for integers beyond 64 bits
+    pub nbformat_compatible: bool,
+    /// Which line endings to write
+    pub eol: Eol,
+    /// Fail if the notebook does not match the nbformat schema before stripping
+    pub validate: bool,
+    /// Fail if the notebook does not match the nbformat schema after stripping
+    pub validate_output: bool,
+    /// Repair common malformations, like code cells without outputs or null metadata
+    pub repair: bool,
+    /// Ignore settings in the notebook's `metadata.nbstripout_fast`
+    pub ignore_notebook_overrides: bool,
+}
+
+/// Settings as written in .git-nbconfig.yaml or in a notebook's `metadata.nbstripout_fast`.
+/// Unset settings are left as they are.
+#[derive(Deserialize, Debug, Default)]
+pub struct StripConfig {
+    /// Presets applied before the other settings
+    pub presets: Option<Vec<String>>,
+    /// Set to false to not strip the `DEFAULT_EXTRA_KEYS`
+    pub use_default_extra_keys: Option<bool>,
+    pub keep_output: Option<bool>,
+    pub keep_count: Option<bool>,
+    pub drop_empty_cells: Option<bool>,
+    pub normalize_outputs: Option<bool>,
+    pub papermill: Option<bool>,
+    pub papermill_clear_parameters: Option<bool>,
+    pub strip_hidden_outputs: Option<bool>,
+    pub upgrade_v3: Option<bool>,
+    pub indent: Option<Indent>,
+    pub nbformat_compatible: Option<bool>,
+    pub eol: Option<Eol>,
+    pub validate: Option<bool>,
+    pub validate_output: Option<bool>,
+    pub repair: Option<bool>,
+    pub tags: Option<CellTags>,
+    pub extra_keys: Option<Vec<String>>,
+    pub keep_keys: Option<Vec<String>>,
+    pub strip_output_types: Option<Vec<String>>,
+    pub keep_output_types: Option<Vec<String>>,
+    pub unknown_output_types: Option<UnknownOutputTypes>,
+    pub strip_regex: Option<String>,
+    pub keep_only_metadata: Option<Vec<String>>,
+    pub keep_only_cell_metadata: Option<Vec<String>>,
+    pub widget_policy: Option<WidgetPolicy>,
+    pub multiline_format: Option<MultilineFormat>,
+    pub normalize_source: Option<Vec<String>>,
+    pub kernelspec: Option<KernelspecOptions>,
+    /// Only allowed in .git-nbconfig.yaml
+    pub allow_notebook_overrides: Option<bool>,
+}
+
+impl StripOptions {
+    /// Apply the settings that are set in `config`, after those of its presets. Extra keys,
+    /// keep keys and output types are added to the existing ones, everything else is replaced.
+    pub fn update(&mut self, config: StripConfig) -> Result<(), String> {
+        for name in config.presets.iter().flatten() {
+            self.update(presets::preset(name)?)?;
+        }
+        self.default_extra_keys = config.use_default_extra_keys.unwrap_or(self.default_extra_keys);
+        self.keep_output = config.keep_output.unwrap_or(self.keep_output);
+        self.keep_count = config.keep_count.unwrap_or(self.keep_count);
+        self.drop_empty_cells = config.drop_empty_cells.unwrap_or(self.drop_empty_cells);
+        self.normalize_outputs = config.normalize_outputs.unwrap_or(self.normalize_outputs);
+        self.papermill = config.papermill.unwrap_or(self.papermill);
+        self.papermill_clear_parameters = config
+            .papermill_clear_parameters
+            .unwrap_or(self.papermill_clear_parameters);
+        self.strip_hidden_outputs = config.strip_hidden_outputs.unwrap_or(self.strip_hidden_outputs);
+        self.upgrade_v3 = config.upgrade_v3.unwrap_or(self.upgrade_v3);
+        self.indent = config.indent.unwrap_or(self.indent);
+        self.nbformat_compatible = config.nbformat_compatible.unwrap_or(self.nbformat_compatible);
+        self.eol = config.eol.unwrap_or(self.eol);
+        self.validate = config.validate.unwrap_or(self.validate);
+        self.validate_output = config.validate_output.unwrap_or(self.validate_output);
+        self.repair = config.repair.unwrap_or(self.repair);
+        if let Some(tags) = config.tags {
+            self.tags = tags;
+        }
+        if let Some(extra_keys) = config.extra_keys {
+            self.extra_keys.extend(extra_keys);
+        }
+        if let Some(keep_keys) = config.keep_keys {
+            self.keep_keys.extend(keep_keys);
+        }
+        if let Some(strip_output_types) = config.strip_output_types {
+            self.strip_output_types.extend(strip_output_types);
+        }
+        if let Some(keep_output_types) = config.keep_output_types {
+            self.keep_output_types.extend(keep_output_types);
+        }
+        self.unknown_output_types = config.unknown_output_types.unwrap_or(self.unknown_output_types);
+        self.strip_regex = config.strip_regex.or(self.strip_regex.take());
+        if config.keep_only_metadata.is_some() {
+            self.keep_only_metadata = config.keep_only_metadata;
+        }
+        if config.keep_only_cell_metadata.is_some() {
+            self.keep_only_cell_metadata = config.keep_only_cell_metadata;
+        }
+        self.widget_policy = config.widget_policy.unwrap_or(self.widget_policy);
+        self.multiline_format = config.multiline_format.unwrap_or(self.multiline_format);
+        if let Some(normalize_source) = config.normalize_source {
+            self.normalize_source = normalize_source;
+        }
+        if let Some(kernelspec) = config.kernelspec {
+            self.kernelspec = kernelspec;
+        }
+        if let Some(allow) = config.allow_notebook_overrides {
+            self.ignore_notebook_overrides = !allow;
+        }
+        Ok(())
+    }
+}
+
+/// The settings for this notebook: `options`, updated with any settings in the notebook's
+/// `metadata.nbstripout_fast` (unless those are ignored). These must be resolved from the
+/// notebook before it is stripped, and used both to strip and to serialize it.
 #[cfg_attr(not(feature = "extension-module"), allow(unused))]
-pub fn strip_output(
-    nb: &mut serde_json::Value,
-    keep_output: bool,
-    keep_count: bool,
-    extra_keys: &Vec<String>,
-    drop_empty_cells: bool,
-    strip_regex: Option<&str>,
-) -> Result<bool, String> {
-    log::debug!(
-        "keep-output: {}, keep-count: {}, extra-keys: {:?}, drop-empty-cells: {}, strip-regex: {:?}",
-        keep_output,
-        keep_count,
-        extra_keys,
-        drop_empty_cells,
-        strip_regex
-    );
-    let mut metadata_keys = Vec::<String>::new();
-    let mut cell_keys = Vec::<String>::new();
+pub fn notebook_options(nb: &serde_json::Value, options: &StripOptions) -> Result<Option<StripOptions>, String> {
+    if options.ignore_notebook_overrides {
+        return Ok(None);
+    }
+    let overrides = match nb.get("metadata").and_then(|m| m.get("nbstripout_fast")) {
+        Some(overrides) => overrides,
+        None => return Ok(None),
+    };
+    let mut config: StripConfig = serde_json::from_value(overrides.clone())
+        .map_err(|e| format!("metadata.nbstripout_fast in the notebook is not valid: {}", e))?;
+    if config.allow_notebook_overrides.take().is_some() {
+        log::warn!("allow_notebook_overrides can not be set in the notebook, ignoring it");
+    }
+    let mut options = options.clone();
+    options.update(config)?;
+    log::debug!("Notebook overrides settings: {:?}", options);
+    Ok(Some(options))
+}
+
+/// Key patterns, each limited to cells or outputs of one type if `Some`
+type TypedKeys = Vec<(Option<String>, KeyPattern)>;
+
+/// The patterns in `keys` that apply to a cell or output of type `type_name`
+fn keys_for_type(keys: &TypedKeys, type_name: &str) -> Vec<KeyPattern> {
+    keys.iter()
+        .filter(|(only, _)| only.as_deref().is_none_or(|only| only == type_name))
+        .map(|(_, pattern)| pattern.clone())
+        .collect()
+}
+
+/// Key patterns split by the part of the notebook they apply to
+#[derive(Debug, Default)]
+struct NamespacedKeys {
+    metadata: Vec<KeyPattern>,
+    cell: TypedKeys,
+    output: TypedKeys,
+}
+
+/// Split `cell.foo`/`metadata.bar`/`output.baz` keys by namespace, erroring if anything else
+/// is passed. Cell keys may be limited to one cell type with `code.foo`, `markdown.foo` or
+/// `raw.foo`, and output keys to one output type with `output:stream.baz`.
+fn split_keys(keys: &[String], kind: &str) -> Result<NamespacedKeys, String> {
+    let mut result = NamespacedKeys::default();
+    let malformed = |key: &String| {
+        format!(
+            "{} '{}' must be of the form cell.foo, code.foo, markdown.foo, raw.foo, metadata.bar or output.baz. Exiting...",
+            kind, key
+        )
+    };
+    for key in keys {
+        let pattern = KeyPattern::parse(key).map_err(|e| format!("{} {}. Exiting...", kind, e))?;
+        let (namespace, pattern) = pattern.split_namespace().ok_or_else(|| malformed(key))?;
+        match namespace.split_once(':') {
+            None if namespace == "metadata" => result.metadata.push(pattern),
+            None if namespace == "cell" => result.cell.push((None, pattern)),
+            None if ["code", "markdown", "raw"].contains(&namespace.as_str()) => {
+                result.cell.push((Some(namespace), pattern))
+            }
+            None if namespace == "output" => result.output.push((None, pattern)),
+            Some(("output", output_type)) => {
+                result.output.push((Some(output_type.to_string()), pattern))
+            }
+            _ => return Err(malformed(key)),
+        }
+    }
+    Ok(result)
+}
+
+/// Metadata keys to keep, with everything else being stripped
+#[derive(Debug)]
+struct Allowlist {
+    /// Matches every key of the metadata
+    all: KeyPattern,
+    keep: Vec<KeyPattern>,
+}
+
+impl Allowlist {
+    /// Parse an allowlist such as `[kernelspec, language_info.name]` for the object at
+    /// `prefix`, e.g. `""` for the notebook metadata or `"metadata."` for a cell's metadata
+    fn parse(keys: &Option<Vec<String>>, kind: &str, prefix: &str) -> Result<Option<Allowlist>, String> {
+        let keys = match keys {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        let parse = |key: &str| {
+            KeyPattern::parse(&format!("{}{}", prefix, key))
+                .map_err(|e| format!("{} key {}. Exiting...", kind, e))
+        };
+        Ok(Some(Allowlist {
+            all: parse("*")?,
+            keep: keys.iter().map(|key| parse(key)).collect::<Result<_, _>>()?,
+        }))
+    }
+
+    /// Strip everything from `value` that is not in the allowlist or `keep_keys`
+    fn apply(&self, value: &mut serde_json::Value, keep_keys: &[KeyPattern]) {
+        let keep: Vec<KeyPattern> = self.keep.iter().chain(keep_keys).cloned().collect();
+        keypath::strip_keys(value, std::slice::from_ref(&self.all), &keep);
+    }
+}
+
+/// An error stripping a notebook
+#[derive(Debug)]
+pub struct StripError {
+    pub message: String,
+    /// Where the notebook does not match the nbformat schema, with `validate` or
+    /// `validate_output`
+    pub violations: Vec<ValidationError>,
+}
+
+impl fmt::Display for StripError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        let violations: Vec<String> = self.violations.iter().map(|v| v.to_string()).collect();
+        if !violations.is_empty() {
+            write!(f, ": {}", violations.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for StripError {
+    fn from(message: String) -> Self {
+        StripError {
+            message,
+            violations: Vec::new(),
+        }
+    }
+}
+
+impl From<&str> for StripError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+/// Strip the notebook, returning the repairs made to it (with `repair`)
+#[cfg_attr(not(feature = "extension-module"), allow(unused))]
+pub fn strip_output(nb: &mut serde_json::Value, options: &StripOptions) -> Result<Vec<String>, StripError> {
+    let strip_regex_obj = options
+        .strip_regex
+        .as_deref()
+        .map(|s| Regex::new(s).map_err(|_| format!("'{}' is not a valid regex.", s)))
+        .transpose()?;
+    if options.zeppelin || zeppelin::is_note(nb) {
+        // There are no schemas or repairs for Zeppelin notes, so say so rather than skip them
+        if options.validate || options.validate_output {
+            return Err("Zeppelin notes can not be validated, only Jupyter notebooks".into());
+        }
+        if options.repair {
+            return Err("Zeppelin notes can not be repaired, only Jupyter notebooks".into());
+        }
+        log::debug!("Stripping Zeppelin note");
+        zeppelin::strip(nb, options.keep_output, strip_regex_obj.as_ref(), options.drop_empty_cells)?;
+        return Ok(Vec::new());
+    }
+    let repairs = match options.repair {
+        true => repair::repair(nb),
+        false => Vec::new(),
+    };
+    if options.upgrade_v3 && v3::is_v3(nb) {
+        log::debug!("Upgrading nbformat 3 notebook to nbformat 4");
+        v3::upgrade(nb);
+    }
+    // Validated after upgrading, as only nbformat 4 notebooks can be validated
+    if options.validate {
+        schema::check(nb, "The notebook")?;
+    }
+    log::debug!("{:?}", options);
+    let keep_count = options.keep_count;
 
     let empty_json: serde_json::Value = serde_json::json!({});
     let notebook_metadata = nb
         .get("metadata")
         .unwrap_or(&empty_json)
         .as_object()
-        .expect("metadata must be an object");
+        .ok_or("Notebook metadata must be an object; notebook is malformed.")?;
 
-    let keep_output = keep_output
+    let keep_output = options.keep_output
         || notebook_metadata
-            .get("keep_output")
+            .get(&options.tags.keep_output)
             .unwrap_or(&empty_json)
             .as_bool()
             .unwrap_or(false);
 
-    // First split the extra keys into metadata and cell and error if anything else is passed
-    for key in extra_keys {
-        if !key.contains(".") {
-            return Err(format!(
-                "extra key '{}' does not contain a . - must be of the form cell.foo or metadata.bar. Exiting...", key
-            ));
-        }
-        let (namespace, subkey) = key.split_once(".").unwrap();
-        if namespace == "metadata" {
-            metadata_keys.push(subkey.to_string());
-        } else if namespace == "cell" {
-            cell_keys.push(subkey.to_string());
-        } else {
-            return Err(format!(
-                "extra key '{}' must be of the form cell.foo or metadata.bar. Exiting...",
-                key
-            ));
-        }
+    let mut all_extra_keys = options.extra_keys.clone();
+    if options.default_extra_keys {
+        all_extra_keys.extend(DEFAULT_EXTRA_KEYS.iter().map(|key| key.to_string()));
+    }
+    let mut extra_keys = split_keys(&all_extra_keys, "extra key")?;
+    if options.papermill {
+        extra_keys.metadata.push(KeyPattern::parse("papermill")?);
+        extra_keys.cell.push((None, KeyPattern::parse("metadata.papermill")?));
     }
+    let keep_keys = split_keys(&options.keep_keys, "keep key")?;
+    let keep_only_metadata =
+        Allowlist::parse(&options.keep_only_metadata, "keep_only_metadata", "")?;
+    let keep_only_cell_metadata =
+        Allowlist::parse(&options.keep_only_cell_metadata, "keep_only_cell_metadata", "metadata.")?;
+    let language_info = Allowlist::parse(
+        &options.kernelspec.language_info,
+        "kernelspec.language_info",
+        "language_info.",
+    )?;
 
     // Remove all keys from metadata
-    let metadata_option: Option<&mut serde_json::Value> = nb.get_mut("metadata");
-    if !metadata_keys.is_empty() {
-        if let Some(metadata) = metadata_option {
-            for field in metadata_keys {
-                pop_recursive(metadata, &field);
-            }
+    if let Some(metadata) = nb.get_mut("metadata") {
+        if !extra_keys.metadata.is_empty() {
+            keypath::strip_keys(metadata, &extra_keys.metadata, &keep_keys.metadata);
+        }
+        if let Some(allowlist) = &keep_only_metadata {
+            allowlist.apply(metadata, &keep_keys.metadata);
+        }
+        kernelspec::normalize(metadata, &options.kernelspec);
+        if let Some(allowlist) = &language_info {
+            allowlist.apply(metadata, &keep_keys.metadata);
         }
     }
 
-    // Now process each cell
-    let cells_option: Option<&mut serde_json::Value> = nb.get_mut("cells");
-    if let Some(cells) = cells_option.and_then(|c| c.as_array_mut()) {
+    // Widget outputs are checked against the state left after stripping the metadata
+    let stored_models = widgets::stored_model_ids(nb.get("metadata"));
+    let mut used_models = HashSet::new();
+
+    let strip_output_types = OutputTypes::parse(&options.strip_output_types, "strip output type")?;
+    let keep_output_types = OutputTypes::parse(&options.keep_output_types, "keep output type")?;
+
+    // Now process each cell, in each worksheet for nbformat 3
+    for cells in v3::cell_lists(nb) {
         // Remove cells that the user wants to drop (e.g. empty cells)
-        if drop_empty_cells {
-            cells.retain(|cell| {
+        if options.drop_empty_cells || !options.normalize_source.is_empty() || options.papermill {
+            let mut invalid_source = None;
+            cells.retain_mut(|cell| {
+                if options.papermill && has_tag(cell, "injected-parameters") {
+                    return false;
+                }
+
+                // Normalise first so that cells that only contained whitespace are dropped
+                let cell_type = cell.get("cell_type").and_then(|t| t.as_str()).unwrap_or("");
+                if options.normalize_source.iter().any(|t| t == cell_type) {
+                    let expand_tabs = cell_type == "code";
+                    if let Some(source) = cell.get_mut(v3::source_key(cell)) {
+                        source::normalize(source, expand_tabs);
+                    }
+                }
+                if !options.drop_empty_cells {
+                    return true;
+                }
+
                 // Source is an array of lines
-                let source: &serde_json::Value =
-                    cell.as_object().expect("Cell must be an object")["source"].borrow();
+                let source = cell.get(v3::source_key(cell)).unwrap_or(&serde_json::Value::Null);
                 if source.is_array() {
                     // If any cell has a line that is not just whitespace, retain it
                     source
@@ -237,51 +687,107 @@ pub fn strip_output(
                 } else if source.is_string() {
                     !source.as_str().unwrap_or("").trim().is_empty()
                 } else {
-                    panic!("Source must be an string or array: {:?}", source);
+                    invalid_source.get_or_insert_with(|| source.clone());
+                    true
                 }
             });
+            if let Some(source) = invalid_source {
+                return Err(format!(
+                    "Source must be a string or array, not {}; notebook is malformed.",
+                    source
+                )
+                .into());
+            }
         }
 
-        let strip_regex_obj = strip_regex
-            .map(|s| Regex::new(s).map_err(|_| format!("'{}' is not a valid regex.", s)))
-            .transpose()?;
-
         // Clean up each cell as required
         for cell_object in cells {
             if !cell_object.is_object() {
                 log::debug!("Skipping non-object cell");
                 continue;
             }
+            let clear_parameters = options.papermill
+                && options.papermill_clear_parameters
+                && has_tag(cell_object, "parameters");
+            let source_key = v3::source_key(cell_object);
             let cell = cell_object.as_object_mut().expect("Cell must be an object");
+            let keep_count = keep_count || has_directive(cell, &options.tags.keep_count);
 
             if cell.contains_key("outputs") {
                 // Must come before `let outputs = ...` to avoid borrowing an immutable reference
                 // and a mutable reference to `cell` simultaneously
-                let keep = determine_keep_output(cell, keep_output, strip_regex_obj.as_ref())?;
+                let keep = determine_keep_output(
+                    cell,
+                    keep_output,
+                    strip_regex_obj.as_ref(),
+                    &strip_output_types,
+                    &keep_output_types,
+                    options.unknown_output_types,
+                    &options.tags,
+                    options.strip_hidden_outputs,
+                )?;
 
                 let outputs = cell["outputs"]
                     .as_array_mut()
-                    .expect("Outputs must be an array");
+                    .ok_or("Outputs must be an array; notebook is malformed.")?;
 
                 // Default behavior (max_size == 0) strips all outputs.
-                if keep.is_empty() {
+                if keep.is_empty() || clear_parameters {
                     outputs.clear();
                 } else {
                     let mut keep_iter = keep.iter();
                     outputs.retain(|_| *keep_iter.next().unwrap());
                 }
 
-                // Strip the counts from the outputs that were kept if not keep_count.
-                if !keep_count {
-                    for output in outputs {
-                        let obj = output.as_object_mut().expect("Output should be an object");
+                widgets::apply_to_outputs(
+                    outputs,
+                    options.widget_policy,
+                    &stored_models,
+                    &mut used_models,
+                );
 
-                        // Null (don't delete) execution_count to satisfy nbformat schema.
-                        if obj.contains_key("execution_count") {
-                            obj.insert("execution_count".to_string(), json!(null));
+                for output in outputs.iter_mut() {
+                    let obj = output
+                        .as_object_mut()
+                        .ok_or("Output should be an object; notebook is malformed.")?;
+
+                    // Strip the counts from the outputs that were kept if not keep_count.
+                    // Null (don't delete) execution_count to satisfy nbformat schema.
+                    if !keep_count && obj.contains_key("execution_count") {
+                        obj.insert("execution_count".to_string(), json!(null));
+                    }
+                    if !keep_count && obj.contains_key("prompt_number") {
+                        obj.insert("prompt_number".to_string(), json!(null));
+                    }
+
+                    // Remove output keys, skipping the lookup in the common case of there being none
+                    if !extra_keys.output.is_empty() {
+                        // nbformat 3 output types are matched by their nbformat 4 names
+                        let output_type = v3::v4_output_type(
+                            obj.get("output_type").and_then(|t| t.as_str()).unwrap_or(""),
+                        )
+                        .to_string();
+                        let strip = keys_for_type(&extra_keys.output, &output_type);
+                        if !strip.is_empty() {
+                            let keep = keys_for_type(&keep_keys.output, &output_type);
+                            keypath::strip_keys(output, &strip, &keep);
                         }
                     }
                 }
+
+                // Last, so that outputs emptied by the above are dropped too
+                if options.normalize_outputs {
+                    outputs::normalize(outputs);
+                }
+                if options.multiline_format != MultilineFormat::Preserve {
+                    for output in outputs.iter_mut() {
+                        multiline::reformat_output(output, options.multiline_format);
+                    }
+                }
+            }
+
+            if let Some(source) = cell.get_mut(source_key) {
+                multiline::reformat(source, options.multiline_format);
             }
 
             // Remove the prompt_number/execution_count, unless directed otherwise
@@ -293,11 +799,39 @@ pub fn strip_output(
             }
 
             // Always remove some metadata
-            for field in &cell_keys {
-                pop_recursive(cell_object, field);
+            let cell_type = cell
+                .get("cell_type")
+                .and_then(|t| t.as_str())
+                .unwrap_or("")
+                .to_string();
+            let mut keep = keys_for_type(&keep_keys.cell, &cell_type);
+            // Cells can protect their own keys with `nbstripout: {keep_keys: [cell.foo]}`
+            if let Some(cell_keep_keys) = cell
+                .get("metadata")
+                .and_then(|m| m.get("nbstripout"))
+                .and_then(|n| n.get("keep_keys"))
+            {
+                let cell_keep_keys: Vec<String> = serde_json::from_value(cell_keep_keys.clone())
+                    .map_err(|e| format!("nbstripout.keep_keys in cell metadata must be a list of strings: {}", e))?;
+                keep.extend(keys_for_type(&split_keys(&cell_keep_keys, "cell keep key")?.cell, &cell_type));
+            }
+            if !extra_keys.cell.is_empty() {
+                let strip = keys_for_type(&extra_keys.cell, &cell_type);
+                keypath::strip_keys(cell_object, &strip, &keep);
+            }
+            if let Some(allowlist) = &keep_only_cell_metadata {
+                allowlist.apply(cell_object, &keep);
             }
         }
     }
 
-    Ok(true)
+    if options.widget_policy == WidgetPolicy::PruneState {
+        widgets::prune_state(nb.get_mut("metadata"), &used_models);
+    }
+
+    if options.validate_output {
+        schema::check(nb, "The stripped notebook")?;
+    }
+
+    Ok(repairs)
 }
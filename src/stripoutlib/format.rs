@@ -0,0 +1,433 @@
+// Writing notebooks back out the way they were written: with the same
+// indentation, escaping of non-ASCII characters and trailing newline, so that
+// stripping an already clean notebook does not change it. Alternatively, like
+// `nbformat.writes` does. Integers too large for 64 bits are read as floats, so
+// are written as floats either way.
+use serde::{Deserialize, Serialize};
+use serde_json::ser::Formatter;
+use serde_json::Value;
+use std::io;
+use std::str::FromStr;
+
+use super::multiline::{self, MultilineFormat};
+use super::StripOptions;
+
+/// How to indent the JSON written out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "IndentSetting")]
+pub enum Indent {
+    /// The same as the input
+    #[default]
+    Auto,
+    /// This many spaces per level
+    Spaces(usize),
+    /// A tab per level
+    Tab,
+    /// Everything on one line
+    Compact,
+}
+
+impl FromStr for Indent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Indent::Auto),
+            "tab" => Ok(Indent::Tab),
+            "compact" => Ok(Indent::Compact),
+            _ => s.parse().map(Indent::Spaces).map_err(|_| {
+                format!(
+                    "'{}' is not a valid indent, must be auto, a number of spaces, tab or compact",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Which line endings to write
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eol {
+    /// The same as the input
+    #[default]
+    Preserve,
+    Lf,
+    Crlf,
+}
+
+impl FromStr for Eol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(Eol::Preserve),
+            "lf" => Ok(Eol::Lf),
+            "crlf" => Ok(Eol::Crlf),
+            _ => Err(format!("'{}' is not a valid eol, must be one of lf, crlf or preserve", s)),
+        }
+    }
+}
+
+const BOM: char = '\u{feff}';
+
+/// `indent` as written in YAML or JSON, where it may be a number
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IndentSetting {
+    Spaces(usize),
+    Name(String),
+}
+
+impl TryFrom<IndentSetting> for Indent {
+    type Error = String;
+
+    fn try_from(setting: IndentSetting) -> Result<Self, Self::Error> {
+        match setting {
+            IndentSetting::Spaces(n) => Ok(Indent::Spaces(n)),
+            IndentSetting::Name(name) => name.parse(),
+        }
+    }
+}
+
+/// The indentation used by `contents`. Notebooks are written by nbformat with
+/// one space, which is also used when there is nothing to go by.
+fn detect_indent(contents: &str) -> Indent {
+    let mut lines = contents.lines().skip_while(|line| line.trim().is_empty());
+    if lines.next().is_none() {
+        return Indent::Spaces(1);
+    }
+    // The first line after the opening bracket is indented by one level
+    match lines.find(|line| !line.trim().is_empty()) {
+        None => Indent::Compact,
+        Some(line) if line.starts_with('\t') => Indent::Tab,
+        Some(line) => match line.len() - line.trim_start_matches(' ').len() {
+            // e.g. an empty object split over two lines
+            0 => Indent::Spaces(1),
+            width => Indent::Spaces(width),
+        },
+    }
+}
+
+/// Does `contents` escape non-ASCII characters (`é`) rather than writing
+/// them as they are? Notebooks are written by nbformat with them as they are.
+fn detect_ensure_ascii(contents: &str) -> bool {
+    if !contents.is_ascii() {
+        return false;
+    }
+    let bytes = contents.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            i += 1;
+            continue;
+        }
+        if bytes.get(i + 1) == Some(&b'u') {
+            let escaped = contents
+                .get(i + 2..i + 6)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+            if escaped.is_some_and(|c| c >= 0x80) {
+                return true;
+            }
+        }
+        // Skip the escaped character, so that `\\u` is not taken as an escape
+        i += 2;
+    }
+    false
+}
+
+/// Format a float like python's `repr`, which `json.dumps` uses: e.g. `1e-05`
+/// and `1e+16` where serde_json writes `1e-5` and `1e16`
+fn python_float(value: f64) -> String {
+    // The shortest digits that round-trip, like python
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("floats are formatted with an exponent");
+    let exponent: i32 = exponent.parse().expect("the exponent is a number");
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => ("-", mantissa),
+        None => ("", mantissa),
+    };
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    // Position of the decimal point relative to the digits
+    let point = exponent + 1;
+    if !(-4 < point && point <= 16) {
+        let (first, rest) = digits.split_at(1);
+        let rest = if rest.is_empty() { String::new() } else { format!(".{}", rest) };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}{}{}e{}{:02}", sign, first, rest, exponent_sign, exponent.abs())
+    } else if point <= 0 {
+        format!("{}0.{}{}", sign, "0".repeat(point.unsigned_abs() as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!("{}{}{}.0", sign, digits, "0".repeat(point as usize - digits.len()))
+    } else {
+        let (whole, fraction) = digits.split_at(point as usize);
+        format!("{}{}.{}", sign, whole, fraction)
+    }
+}
+
+/// Like serde_json's `PrettyFormatter`, with any indent (or none), optionally
+/// escaping non-ASCII characters like python's `ensure_ascii` and writing
+/// floats like python
+struct NotebookFormatter {
+    indent: Option<Vec<u8>>,
+    ensure_ascii: bool,
+    python_floats: bool,
+    current_indent: usize,
+    has_value: bool,
+}
+
+impl NotebookFormatter {
+    fn new(indent: Indent, ensure_ascii: bool, python_floats: bool) -> Self {
+        let indent = match indent {
+            Indent::Spaces(width) => Some(vec![b' '; width]),
+            Indent::Tab => Some(vec![b'\t']),
+            Indent::Compact | Indent::Auto => None,
+        };
+        NotebookFormatter {
+            indent,
+            ensure_ascii,
+            python_floats,
+            current_indent: 0,
+            has_value: false,
+        }
+    }
+
+    fn newline<W: ?Sized + io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        if let Some(indent) = &self.indent {
+            writer.write_all(b"\n")?;
+            for _ in 0..self.current_indent {
+                writer.write_all(indent)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn begin<W: ?Sized + io::Write>(&mut self, writer: &mut W, bracket: &[u8]) -> io::Result<()> {
+        self.current_indent += 1;
+        self.has_value = false;
+        writer.write_all(bracket)
+    }
+
+    fn end<W: ?Sized + io::Write>(&mut self, writer: &mut W, bracket: &[u8]) -> io::Result<()> {
+        self.current_indent -= 1;
+        if self.has_value {
+            self.newline(writer)?;
+        }
+        writer.write_all(bracket)
+    }
+
+    fn begin_value<W: ?Sized + io::Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        if !first {
+            writer.write_all(b",")?;
+        }
+        self.newline(writer)
+    }
+}
+
+impl Formatter for NotebookFormatter {
+    fn begin_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.begin(writer, b"[")
+    }
+
+    fn end_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.end(writer, b"]")
+    }
+
+    fn begin_array_value<W: ?Sized + io::Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        self.begin_value(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + io::Write>(&mut self, _writer: &mut W) -> io::Result<()> {
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn begin_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.begin(writer, b"{")
+    }
+
+    fn end_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.end(writer, b"}")
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        self.begin_value(writer, first)
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        match self.indent {
+            Some(_) => writer.write_all(b": "),
+            None => writer.write_all(b":"),
+        }
+    }
+
+    fn end_object_value<W: ?Sized + io::Write>(&mut self, _writer: &mut W) -> io::Result<()> {
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn write_f64<W: ?Sized + io::Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        if self.python_floats {
+            writer.write_all(python_float(value).as_bytes())
+        } else {
+            serde_json::ser::CompactFormatter.write_f64(writer, value)
+        }
+    }
+
+    fn write_string_fragment<W: ?Sized + io::Write>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()> {
+        if !self.ensure_ascii || fragment.is_ascii() {
+            return writer.write_all(fragment.as_bytes());
+        }
+        let mut units = [0; 2];
+        for c in fragment.chars() {
+            if c.is_ascii() {
+                writer.write_all(&[c as u8])?;
+            } else {
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sort the keys of all objects in `value`, like `json.dumps(sort_keys=True)`
+fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Object(obj) => {
+            let mut entries: Vec<(String, Value)> = std::mem::take(obj).into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (_, value) in entries.iter_mut() {
+                sort_keys(value);
+            }
+            *obj = entries.into_iter().collect();
+        }
+        Value::Array(arr) => arr.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
+
+fn is_json_mimetype(mimetype: &str) -> bool {
+    mimetype == "application/json"
+        || (mimetype.starts_with("application/") && mimetype.ends_with("+json"))
+}
+
+/// Join, then split again, the multiline strings of a mime bundle. Like nbformat,
+/// this joins all but JSON data, but only splits text.
+fn resplit_mime_bundle(bundle: &mut Value) {
+    if let Some(bundle) = bundle.as_object_mut() {
+        for (mimetype, value) in bundle.iter_mut() {
+            if !is_json_mimetype(mimetype) {
+                multiline::reformat(value, MultilineFormat::String);
+            }
+            if multiline::is_multiline_mimetype(mimetype) {
+                multiline::reformat(value, MultilineFormat::Lines);
+            }
+        }
+    }
+}
+
+/// The notebook as `nbformat.writes` writes it after reading it: with its
+/// multiline strings joined by `rejoin_lines` and split again by `split_lines`,
+/// and without what `strip_transient` strips
+fn nbformat_document(nb: &Value) -> Value {
+    let mut nb = nb.clone();
+    if let Some(metadata) = nb.get_mut("metadata").and_then(|m| m.as_object_mut()) {
+        metadata.retain(|key, _| !["orig_nbformat", "orig_nbformat_minor", "signature"].contains(&key.as_str()));
+    }
+    for cell in nb.get_mut("cells").and_then(|c| c.as_array_mut()).into_iter().flatten() {
+        if let Some(metadata) = cell.get_mut("metadata").and_then(|m| m.as_object_mut()) {
+            metadata.retain(|key, _| key != "trusted");
+        }
+        if let Some(source) = cell.get_mut("source") {
+            multiline::reformat(source, MultilineFormat::String);
+            multiline::reformat(source, MultilineFormat::Lines);
+        }
+        if let Some(attachments) = cell.get_mut("attachments").and_then(|a| a.as_object_mut()) {
+            attachments.values_mut().for_each(resplit_mime_bundle);
+        }
+        if cell.get("cell_type").and_then(|t| t.as_str()) != Some("code") {
+            continue;
+        }
+        for output in cell.get_mut("outputs").and_then(|o| o.as_array_mut()).into_iter().flatten() {
+            match output.get("output_type").and_then(|t| t.as_str()) {
+                Some("execute_result") | Some("display_data") => {
+                    if let Some(data) = output.get_mut("data") {
+                        resplit_mime_bundle(data);
+                    }
+                }
+                Some("stream") => {
+                    if let Some(text) = output.get_mut("text") {
+                        multiline::reformat(text, MultilineFormat::String);
+                        multiline::reformat(text, MultilineFormat::Lines);
+                    }
+                }
+                Some(_) => {
+                    if let Some(text) = output.get_mut("text") {
+                        multiline::reformat(text, MultilineFormat::String);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    sort_keys(&mut nb);
+    nb
+}
+
+/// Parse a notebook, which may start with a byte order mark
+#[cfg_attr(not(feature = "extension-module"), allow(unused))]
+pub fn parse(contents: &str) -> Result<Value, String> {
+    serde_json::from_str(contents.strip_prefix(BOM).unwrap_or(contents))
+        .map_err(|e| format!("JSON was not well-formatted: {:?}", e))
+}
+
+/// Serialize a notebook in the style of `original`, the notebook as it was
+/// read, unless the options say how to indent it or to write it like nbformat
+#[cfg_attr(not(feature = "extension-module"), allow(unused))]
+pub fn serialize(nb: &Value, original: &str, options: &StripOptions) -> Result<String, String> {
+    let (bom, original) = match original.strip_prefix(BOM) {
+        Some(original) => (true, original),
+        None => (false, original),
+    };
+    let nbformat_nb;
+    let (nb, formatter) = if options.nbformat_compatible {
+        nbformat_nb = nbformat_document(nb);
+        (&nbformat_nb, NotebookFormatter::new(Indent::Spaces(1), false, true))
+    } else {
+        let indent = match options.indent {
+            Indent::Auto => detect_indent(original),
+            indent => indent,
+        };
+        (nb, NotebookFormatter::new(indent, detect_ensure_ascii(original), false))
+    };
+    let mut ser = serde_json::Serializer::with_formatter(Vec::new(), formatter);
+    nb.serialize(&mut ser).map_err(|e| {
+        format!(
+            "Unable to serialize notebook. Likely an internal error: {:?}",
+            e
+        )
+    })?;
+    let mut contents = String::from_utf8(ser.into_inner()).map_err(|e| format!("{:?}", e))?;
+
+    // Keep the newline at the end of the file, if there was one
+    if original.ends_with('\n') && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    // The only newlines written are between values, those in strings are escaped
+    let crlf = match options.eol {
+        Eol::Preserve => original.contains("\r\n"),
+        Eol::Lf => false,
+        Eol::Crlf => true,
+    };
+    if crlf {
+        contents = contents.replace('\n', "\r\n");
+    }
+    if bom {
+        contents.insert(0, BOM);
+    }
+    Ok(contents)
+}
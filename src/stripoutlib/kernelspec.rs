@@ -0,0 +1,46 @@
+// Normalisation of `metadata.kernelspec`, which changes with the environment a
+// notebook happens to be run in.
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Settings for normalising the kernelspec and language_info
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct KernelspecOptions {
+    /// Always set `kernelspec.name` to this
+    pub name: Option<String>,
+    /// Always set `kernelspec.display_name` to this
+    pub display_name: Option<String>,
+    /// Replace kernel names and display names found as keys with their values,
+    /// e.g. `conda-env-myenv-py: python3`
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// If set, only these keys are kept in `language_info`
+    pub language_info: Option<Vec<String>>,
+}
+
+/// Pin and rename the `name` and `display_name` of the notebook's kernelspec
+pub fn normalize(metadata: &mut Value, options: &KernelspecOptions) {
+    let kernelspec = match metadata
+        .get_mut("kernelspec")
+        .and_then(|k| k.as_object_mut())
+    {
+        Some(kernelspec) => kernelspec,
+        None => return,
+    };
+    for (key, pinned) in [("name", &options.name), ("display_name", &options.display_name)] {
+        let canonical = match pinned {
+            Some(pinned) => Some(pinned.clone()),
+            None => kernelspec
+                .get(key)
+                .and_then(|value| value.as_str())
+                .and_then(|value| options.aliases.get(value))
+                .cloned(),
+        };
+        if let Some(canonical) = canonical {
+            if kernelspec.contains_key(key) {
+                kernelspec.insert(key.to_string(), Value::String(canonical));
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+// Key paths used by `extra_keys` and `keep_keys`.
+//
+// A path is a dotted list of keys such as `cell.metadata.jupyter.source_hidden`.
+// Besides plain keys, a path may contain:
+//
+// * `"a.b"`: a quoted key, which may contain dots (escape `"` and `\` with `\`)
+// * `*`: any single key of an object
+// * `**`: any number of keys or indices, including none
+// * `[0]`: an index into an array
+// * `[*]`: any index of an array
+//
+// e.g. `metadata."application/vnd.jupyter.widget-state+json".state` or
+// `cell.metadata.tags[0]`.
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    QuotedKey(String),
+    Index(usize),
+    AnyKey,
+    AnyIndex,
+    AnyDepth,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyPattern {
+    segments: Vec<Segment>,
+}
+
+/// One step of a concrete path through a JSON value
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum PathElem {
+    Key(String),
+    Index(usize),
+}
+
+impl fmt::Display for PathElem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathElem::Key(key) => write!(f, ".{}", key),
+            PathElem::Index(index) => write!(f, "[{}]", index),
+        }
+    }
+}
+
+/// Result of matching a pattern against a concrete path.
+#[derive(Debug, Default, Clone, Copy)]
+struct Match {
+    /// The pattern matches the path itself
+    full: bool,
+    /// The pattern could match something below the path
+    descendant: bool,
+}
+
+impl Match {
+    fn or(self, other: Match) -> Match {
+        Match {
+            full: self.full || other.full,
+            descendant: self.descendant || other.descendant,
+        }
+    }
+}
+
+/// Parse a quoted key, starting just after the opening quote. Returns the key
+/// and the rest of the input after the closing quote.
+fn parse_quoted(input: &str) -> Result<(String, &str), String> {
+    let mut key = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((key, &input[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, escaped)) => key.push(escaped),
+                None => break,
+            },
+            _ => key.push(c),
+        }
+    }
+    Err("unterminated quoted key".to_string())
+}
+
+/// Parse an index, starting just after the opening bracket. Returns the
+/// segment and the rest of the input after the closing bracket.
+fn parse_index(input: &str) -> Result<(Segment, &str), String> {
+    let (index, rest) = input
+        .split_once(']')
+        .ok_or("missing ] after array index")?;
+    let segment = match index {
+        "*" => Segment::AnyIndex,
+        _ => Segment::Index(
+            index
+                .parse()
+                .map_err(|_| format!("'{}' is not a valid array index", index))?,
+        ),
+    };
+    Ok((segment, rest))
+}
+
+impl KeyPattern {
+    pub fn parse(pattern: &str) -> Result<KeyPattern, String> {
+        let error = |e: String| format!("'{}' is not valid: {}", pattern, e);
+        let mut segments = Vec::new();
+        let mut rest = pattern;
+        loop {
+            // A key, optionally followed by any number of indices
+            let segment = if let Some(quoted) = rest.strip_prefix('"') {
+                let (key, after) = parse_quoted(quoted).map_err(error)?;
+                rest = after;
+                Segment::QuotedKey(key)
+            } else {
+                let end = rest.find(['.', '[', '"']).unwrap_or(rest.len());
+                let (key, after) = rest.split_at(end);
+                rest = after;
+                match key {
+                    "" => return Err(error("it contains an empty key".to_string())),
+                    "*" => Segment::AnyKey,
+                    "**" => Segment::AnyDepth,
+                    _ => Segment::Key(key.to_string()),
+                }
+            };
+            // `**.**` is the same as `**` and only makes matching slower
+            if !(segment == Segment::AnyDepth && segments.last() == Some(&Segment::AnyDepth)) {
+                segments.push(segment);
+            }
+            while let Some(index) = rest.strip_prefix('[') {
+                let (segment, after) = parse_index(index).map_err(error)?;
+                segments.push(segment);
+                rest = after;
+            }
+
+            match rest.strip_prefix('.') {
+                Some(after) => rest = after,
+                None if rest.is_empty() => break,
+                None => return Err(error(format!("unexpected '{}'", rest))),
+            }
+        }
+        Ok(KeyPattern { segments })
+    }
+
+    /// Split off the first key of the path, e.g. `cell` in `cell.metadata.foo`.
+    /// Returns `None` if it is not a plain key or nothing follows it.
+    pub fn split_namespace(mut self) -> Option<(String, KeyPattern)> {
+        if self.segments.len() < 2 {
+            return None;
+        }
+        match self.segments.remove(0) {
+            Segment::Key(key) | Segment::QuotedKey(key) => Some((key, self)),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, path: &[PathElem]) -> Match {
+        match_segments(&self.segments, path)
+    }
+
+    /// Every path in `value` matched by this pattern
+    fn collect(&self, value: &Value) -> Vec<Vec<PathElem>> {
+        let mut out = Vec::new();
+        collect_segments(value, &self.segments, &mut Vec::new(), &mut out);
+        out
+    }
+}
+
+// For backwards compatibility, consecutive unquoted keys also match a single
+// key containing dots, e.g. `a.b.c` matches {"a.b": {"c": 1}} as well as
+// {"a": {"b": {"c": 1}}}. Returns how many segments each such key would use.
+fn joined_keys(segments: &[Segment]) -> impl Iterator<Item = (usize, String)> + '_ {
+    let keys: Vec<&str> = segments
+        .iter()
+        .map_while(|s| match s {
+            Segment::Key(key) => Some(key.as_str()),
+            _ => None,
+        })
+        .collect();
+    (2..=keys.len()).map(move |n| (n, keys[..n].join(".")))
+}
+
+fn match_segments(segments: &[Segment], path: &[PathElem]) -> Match {
+    let (head, tail) = match path.split_first() {
+        Some(x) => x,
+        None => {
+            return Match {
+                full: segments.iter().all(|s| *s == Segment::AnyDepth),
+                descendant: !segments.is_empty(),
+            }
+        }
+    };
+    let first = match segments.first() {
+        Some(x) => x,
+        None => return Match::default(),
+    };
+    match (first, head) {
+        (Segment::Key(key), PathElem::Key(head)) => {
+            let mut result = Match::default();
+            if key == head {
+                result = result.or(match_segments(&segments[1..], tail));
+            }
+            for (used, joined) in joined_keys(segments) {
+                if &joined == head {
+                    result = result.or(match_segments(&segments[used..], tail));
+                }
+            }
+            result
+        }
+        (Segment::QuotedKey(key), PathElem::Key(head)) if key == head => {
+            match_segments(&segments[1..], tail)
+        }
+        (Segment::Index(index), PathElem::Index(head)) if index == head => {
+            match_segments(&segments[1..], tail)
+        }
+        (Segment::AnyKey, PathElem::Key(_)) | (Segment::AnyIndex, PathElem::Index(_)) => {
+            match_segments(&segments[1..], tail)
+        }
+        (Segment::AnyDepth, _) => {
+            match_segments(&segments[1..], path).or(match_segments(segments, tail))
+        }
+        _ => Match::default(),
+    }
+}
+
+/// The children of an object or array, with the path element leading to each
+fn children(value: &Value) -> Vec<(PathElem, &Value)> {
+    match value {
+        Value::Object(obj) => obj
+            .iter()
+            .map(|(k, v)| (PathElem::Key(k.clone()), v))
+            .collect(),
+        Value::Array(arr) => arr
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (PathElem::Index(i), v))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn child<'a>(value: &'a Value, elem: &PathElem) -> Option<&'a Value> {
+    match elem {
+        PathElem::Key(key) => value.as_object()?.get(key),
+        PathElem::Index(index) => value.as_array()?.get(*index),
+    }
+}
+
+fn collect_segments(
+    value: &Value,
+    segments: &[Segment],
+    prefix: &mut Vec<PathElem>,
+    out: &mut Vec<Vec<PathElem>>,
+) {
+    let (first, rest) = match segments.split_first() {
+        Some(x) => x,
+        None => {
+            out.push(prefix.clone());
+            return;
+        }
+    };
+    let mut descend = |elem: PathElem, rest: &[Segment]| {
+        if let Some(next) = child(value, &elem) {
+            prefix.push(elem);
+            collect_segments(next, rest, prefix, out);
+            prefix.pop();
+        }
+    };
+    match first {
+        Segment::Key(key) => {
+            descend(PathElem::Key(key.clone()), rest);
+            for (used, joined) in joined_keys(segments) {
+                descend(PathElem::Key(joined), &segments[used..]);
+            }
+        }
+        Segment::QuotedKey(key) => descend(PathElem::Key(key.clone()), rest),
+        Segment::Index(index) => descend(PathElem::Index(*index), rest),
+        Segment::AnyKey | Segment::AnyIndex => {
+            for (elem, _) in children(value) {
+                let wanted = match elem {
+                    PathElem::Key(_) => *first == Segment::AnyKey,
+                    PathElem::Index(_) => *first == Segment::AnyIndex,
+                };
+                if wanted {
+                    descend(elem, rest);
+                }
+            }
+        }
+        Segment::AnyDepth => {
+            // Zero keys...
+            collect_segments(value, rest, prefix, out);
+            // ...or one more
+            for (elem, next) in children(value) {
+                prefix.push(elem);
+                collect_segments(next, segments, prefix, out);
+                prefix.pop();
+            }
+        }
+    }
+}
+
+fn get_path_mut<'a>(value: &'a mut Value, path: &[PathElem]) -> Option<&'a mut Value> {
+    path.iter().try_fold(value, |current, elem| match elem {
+        PathElem::Key(key) => current.as_object_mut()?.get_mut(key),
+        PathElem::Index(index) => current.as_array_mut()?.get_mut(*index),
+    })
+}
+
+fn remove_path(value: &mut Value, path: &[PathElem]) {
+    let (last, parent) = match path.split_last() {
+        Some(x) => x,
+        None => return,
+    };
+    match (get_path_mut(value, parent), last) {
+        (Some(Value::Object(obj)), PathElem::Key(key)) => {
+            obj.remove(key);
+        }
+        (Some(Value::Array(arr)), PathElem::Index(index)) if *index < arr.len() => {
+            arr.remove(*index);
+        }
+        _ => {}
+    }
+}
+
+/// Remove `path` from `value` unless a keep pattern protects it. When a keep
+/// pattern only covers part of the value at `path`, the rest is removed.
+fn strip_path(value: &mut Value, path: &mut Vec<PathElem>, keep: &[KeyPattern]) {
+    let mut descendant_kept = false;
+    for pattern in keep {
+        // Keeping a key also keeps everything below it
+        if (1..=path.len()).any(|n| pattern.matches(&path[..n]).full) {
+            return;
+        }
+        descendant_kept |= pattern.matches(path).descendant;
+    }
+    if !descendant_kept {
+        log::debug!(
+            "Removing {}",
+            path.iter().map(|e| e.to_string()).collect::<String>()
+        );
+        remove_path(value, path);
+        return;
+    }
+
+    let elems: Vec<PathElem> = match get_path_mut(value, path) {
+        Some(node) => children(node).into_iter().map(|(elem, _)| elem).collect(),
+        None => return,
+    };
+    // Backwards so removing an array element does not shift the ones left to visit
+    for elem in elems.into_iter().rev() {
+        path.push(elem);
+        strip_path(value, path, keep);
+        path.pop();
+    }
+    // Only a full match keeps a key, so a scalar, or what is left empty, goes
+    if get_path_mut(value, path).is_some_and(|node| children(node).is_empty()) {
+        remove_path(value, path);
+    }
+}
+
+/// Remove everything matched by the `strip` patterns from `value`, except what
+/// is matched by the `keep` patterns.
+pub fn strip_keys(value: &mut Value, strip: &[KeyPattern], keep: &[KeyPattern]) {
+    let mut paths: Vec<Vec<PathElem>> = strip.iter().flat_map(|p| p.collect(value)).collect();
+    paths.sort();
+    paths.dedup();
+    // Backwards so removing an array element does not shift the paths left to
+    // visit. This also visits nested matches before the keys containing them.
+    for mut path in paths.into_iter().rev() {
+        strip_path(value, &mut path, keep);
+    }
+}
@@ -0,0 +1,109 @@
+// Multiline strings, which nbformat allows to be either a single string or a
+// list of lines (each keeping its line ending).
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// How multiline strings (sources, stream text and textual output data) are written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultilineFormat {
+    /// Leave them as they are
+    #[default]
+    Preserve,
+    /// A list of lines, as written by nbformat
+    Lines,
+    /// A single string
+    String,
+}
+
+impl FromStr for MultilineFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(MultilineFormat::Preserve),
+            "lines" => Ok(MultilineFormat::Lines),
+            "string" => Ok(MultilineFormat::String),
+            _ => Err(format!(
+                "'{}' is not a valid multiline format, must be one of lines, string or preserve",
+                s
+            )),
+        }
+    }
+}
+
+/// Join a multiline string (either a string or a list of lines) into one string
+pub fn join(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(lines) => lines
+            .iter()
+            .map(|line| line.as_str())
+            .collect::<Option<Vec<&str>>>()
+            .map(|lines| lines.concat()),
+        _ => None,
+    }
+}
+
+/// Split a string into lines, keeping the line endings, like python's
+/// `str.splitlines(True)` which nbformat uses
+pub fn split(s: &str) -> Value {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let end = match c {
+            '\r' => match chars.peek() {
+                Some((_, '\n')) => {
+                    chars.next();
+                    i + 2
+                }
+                _ => i + 1,
+            },
+            '\n' | '\x0b' | '\x0c' | '\x1c' | '\x1d' | '\x1e' | '\u{85}' | '\u{2028}'
+            | '\u{2029}' => i + c.len_utf8(),
+            _ => continue,
+        };
+        lines.push(Value::String(s[start..end].to_string()));
+        start = end;
+    }
+    if start < s.len() {
+        lines.push(Value::String(s[start..].to_string()));
+    }
+    Value::Array(lines)
+}
+
+/// Rewrite a multiline string in the given format. Values that are not
+/// multiline strings are left alone.
+pub fn reformat(value: &mut Value, format: MultilineFormat) {
+    let reformatted = match (format, &*value) {
+        (MultilineFormat::Lines, Value::String(s)) => split(s),
+        (MultilineFormat::String, Value::Array(_)) => match join(value) {
+            Some(s) => Value::String(s),
+            None => return,
+        },
+        _ => return,
+    };
+    *value = reformatted;
+}
+
+/// Is output data of this MIME type a multiline string? JSON data and binary
+/// data (such as base64 encoded images) are left alone, as nbformat does.
+pub fn is_multiline_mimetype(mimetype: &str) -> bool {
+    mimetype.starts_with("text/") || mimetype == "application/javascript" || mimetype == "image/svg+xml"
+}
+
+/// Rewrite the multiline strings of an output in the given format
+pub fn reformat_output(output: &mut Value, format: MultilineFormat) {
+    if let Some(text) = output.get_mut("text") {
+        reformat(text, format);
+    }
+    if let Some(data) = output.get_mut("data").and_then(|d| d.as_object_mut()) {
+        for (mimetype, value) in data.iter_mut() {
+            if is_multiline_mimetype(mimetype) {
+                reformat(value, format);
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Cell outputs: their types, and normalisation of the ones kept.
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+use super::multiline;
+use super::v3;
+
+fn is_stream(output: &Value) -> bool {
+    output.get("output_type").and_then(|t| t.as_str()) == Some("stream")
+}
+
+/// Does the output show nothing?
+fn is_empty(output: &Value) -> bool {
+    match output.get("output_type").and_then(|t| t.as_str()) {
+        Some("stream") => output
+            .get("text")
+            .and_then(multiline::join)
+            .is_some_and(|text| text.is_empty()),
+        Some("display_data") | Some("execute_result") | Some("update_display_data") => output
+            .get("data")
+            .and_then(|data| data.as_object())
+            .is_some_and(|data| data.is_empty()),
+        _ => false,
+    }
+}
+
+/// Drop outputs with no content and merge consecutive stream outputs to the same
+/// stream, like nbformat does
+pub fn normalize(outputs: &mut Vec<Value>) {
+    outputs.retain(|output| !is_empty(output));
+
+    let mut merged: Vec<Value> = Vec::with_capacity(outputs.len());
+    for output in outputs.drain(..) {
+        if let Some(previous) = merged.last_mut() {
+            if is_stream(previous) && is_stream(&output) && v3::stream_name(previous) == v3::stream_name(&output) {
+                let previous_text = previous.get("text").and_then(multiline::join);
+                let text = output.get("text").and_then(multiline::join);
+                if let (Some(previous_text), Some(text)) = (previous_text, text) {
+                    let joined = previous_text + &text;
+                    // Keep the representation of the first output
+                    previous["text"] = if previous["text"].is_array() {
+                        multiline::split(&joined)
+                    } else {
+                        Value::String(joined)
+                    };
+                    continue;
+                }
+            }
+        }
+        merged.push(output);
+    }
+    *outputs = merged;
+}
+
+const OUTPUT_TYPES: [&str; 5] = [
+    "stream",
+    "display_data",
+    "execute_result",
+    "update_display_data",
+    "error",
+];
+const STREAM_NAMES: [&str; 2] = ["stdout", "stderr"];
+
+/// What to do with outputs of a type that is not in the notebook format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownOutputTypes {
+    /// Treat them like any other output
+    #[default]
+    Keep,
+    /// Always strip them
+    Strip,
+    /// Refuse to process the notebook
+    Error,
+}
+
+impl FromStr for UnknownOutputTypes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(UnknownOutputTypes::Keep),
+            "strip" => Ok(UnknownOutputTypes::Strip),
+            "error" => Ok(UnknownOutputTypes::Error),
+            _ => Err(format!(
+                "'{}' is not a valid unknown output types policy, must be one of keep, strip or error",
+                s
+            )),
+        }
+    }
+}
+
+/// The (nbformat 4) type of an output, if it is one of the types in the notebook format
+pub fn known_type(output: &Value) -> Option<&str> {
+    output
+        .get("output_type")
+        .and_then(|t| t.as_str())
+        .map(v3::v4_output_type)
+        .filter(|t| OUTPUT_TYPES.contains(t))
+}
+
+/// A set of output types, such as `error` or `stream:stderr` for one stream only
+#[derive(Debug, Default)]
+pub struct OutputTypes(Vec<(String, Option<String>)>);
+
+impl OutputTypes {
+    pub fn parse(types: &[String], kind: &str) -> Result<OutputTypes, String> {
+        let parsed = types
+            .iter()
+            .map(|output_type| {
+                let (name, stream) = match output_type.split_once(':') {
+                    Some(("stream", stream)) if STREAM_NAMES.contains(&stream) => {
+                        ("stream", Some(stream.to_string()))
+                    }
+                    Some(_) => ("", None),
+                    None => (output_type.as_str(), None),
+                };
+                if !OUTPUT_TYPES.contains(&name) {
+                    return Err(format!(
+                        "{} '{}' must be one of stream, stream:stdout, stream:stderr, display_data, \
+                         execute_result, update_display_data or error. Exiting...",
+                        kind, output_type
+                    ));
+                }
+                Ok((name.to_string(), stream))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(OutputTypes(parsed))
+    }
+
+    pub fn matches(&self, output: &Value) -> bool {
+        let output_type = known_type(output);
+        let stream = v3::stream_name(output);
+        self.0.iter().any(|(name, wanted_stream)| {
+            output_type == Some(name.as_str())
+                && wanted_stream.as_ref().is_none_or(|s| stream == Some(s.as_str()))
+        })
+    }
+}
@@ -0,0 +1,55 @@
+// Named presets bundling the settings for the metadata added by common notebook
+// front-ends, selected with `presets: [colab, vscode]`.
+use super::StripConfig;
+
+const PRESET_NAMES: &str = "colab, databricks, jupyterlab, kaggle or vscode";
+
+fn keys(keys: &[&str]) -> Option<Vec<String>> {
+    Some(keys.iter().map(|key| key.to_string()).collect())
+}
+
+/// The settings of the preset called `name`
+pub fn preset(name: &str) -> Result<StripConfig, String> {
+    let config = match name {
+        "colab" => StripConfig {
+            extra_keys: keys(&[
+                "metadata.colab",
+                "metadata.accelerator",
+                "metadata.gpuClass",
+                "cell.metadata.id",
+                "cell.metadata.colab",
+                "cell.metadata.outputId",
+                "output.metadata.id",
+                "output.metadata.outputId",
+            ]),
+            ..Default::default()
+        },
+        "databricks" => StripConfig {
+            extra_keys: keys(&[
+                r#"metadata."application/vnd.databricks.v1+notebook".notebookOrigID"#,
+                r#"metadata."application/vnd.databricks.v1+notebook".widgets"#,
+                r#"cell.metadata."application/vnd.databricks.v1+cell""#,
+            ]),
+            ..Default::default()
+        },
+        "jupyterlab" => StripConfig {
+            extra_keys: keys(&["cell.metadata.jupyter", "metadata.toc-autonumbering"]),
+            ..Default::default()
+        },
+        "kaggle" => StripConfig {
+            extra_keys: keys(&["metadata.kaggle", "cell.metadata.trusted"]),
+            ..Default::default()
+        },
+        "vscode" => StripConfig {
+            extra_keys: keys(&["metadata.vscode", "metadata.interpreter", "cell.metadata.vscode"]),
+            ..Default::default()
+        },
+        _ => {
+            return Err(format!(
+                "'{}' is not a known preset, must be one of {}. Exiting...",
+                name, PRESET_NAMES
+            ))
+        }
+    };
+    Ok(config)
+}
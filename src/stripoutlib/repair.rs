@@ -0,0 +1,166 @@
+// Repairs for common malformations, which would otherwise fail stripping (`repair: true`).
+// Each repair is described with a JSON pointer to what was fixed, e.g.
+// `/cells/2: added the missing outputs`.
+use serde_json::{json, Map, Value};
+
+use super::v3;
+
+/// Make the metadata of `map` an object if it is missing, null or not an object
+fn repair_metadata(map: &mut Map<String, Value>, pointer: &str, repairs: &mut Vec<String>) {
+    let fix = match map.get("metadata") {
+        Some(Value::Object(_)) => return,
+        None => "added the missing metadata",
+        Some(Value::Null) => "replaced null metadata with {}",
+        Some(_) => "replaced metadata that was not an object with {}",
+    };
+    map.insert("metadata".to_string(), json!({}));
+    repairs.push(format!("{}: {}", pointer, fix));
+}
+
+/// A line of source that is not a string, as a string
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    }
+}
+
+fn repair_source(cell: &mut Map<String, Value>, key: &str, pointer: &str, repairs: &mut Vec<String>) {
+    let fix = match cell.get_mut(key) {
+        Some(Value::String(_)) => return,
+        Some(Value::Array(lines)) => {
+            if lines.iter().all(|line| line.is_string()) {
+                return;
+            }
+            for line in lines.iter_mut().filter(|line| !line.is_string()) {
+                *line = Value::String(to_text(line));
+            }
+            repairs.push(format!("{}/{}: converted lines that were not strings", pointer, key));
+            return;
+        }
+        None => "added the missing source",
+        Some(Value::Null) => "replaced null source with \"\"",
+        Some(_) => "converted source that was not a string to a string",
+    };
+    let source = cell.get(key).map(to_text).unwrap_or_default();
+    cell.insert(key.to_string(), Value::String(source));
+    repairs.push(format!("{}: {}", pointer, fix));
+}
+
+fn repair_code_cell(
+    cell: &mut Map<String, Value>,
+    is_v3: bool,
+    pointer: &str,
+    repairs: &mut Vec<String>,
+) {
+    match cell.get_mut("outputs") {
+        Some(Value::Array(outputs)) => {
+            let before = outputs.len();
+            outputs.retain(|output| output.is_object());
+            if outputs.len() != before {
+                repairs.push(format!("{}: removed outputs that were not objects", pointer));
+            }
+        }
+        None => {
+            cell.insert("outputs".to_string(), json!([]));
+            repairs.push(format!("{}: added the missing outputs", pointer));
+        }
+        Some(_) => {
+            cell.insert("outputs".to_string(), json!([]));
+            repairs.push(format!("{}: replaced outputs that were not a list with []", pointer));
+        }
+    }
+    for (index, output) in cell["outputs"]
+        .as_array_mut()
+        .expect("outputs was just repaired")
+        .iter_mut()
+        .enumerate()
+    {
+        let output = output.as_object_mut().expect("outputs was just repaired");
+        let output_type = output.get("output_type").and_then(|t| t.as_str());
+        let has_metadata = matches!(
+            output_type,
+            Some("display_data" | "execute_result" | "pyout")
+        );
+        if has_metadata || output.contains_key("metadata") {
+            repair_metadata(output, &format!("{}/outputs/{}", pointer, index), repairs);
+        }
+    }
+    // nbformat 3 cells have an optional prompt_number instead
+    if !is_v3 && !cell.contains_key("execution_count") {
+        cell.insert("execution_count".to_string(), Value::Null);
+        repairs.push(format!("{}: added the missing execution_count", pointer));
+    }
+}
+
+fn repair_cell(cell: &mut Value, is_v3: bool, pointer: &str, repairs: &mut Vec<String>) {
+    let source_key = v3::source_key(cell);
+    let cell = match cell.as_object_mut() {
+        Some(cell) => cell,
+        None => return,
+    };
+    repair_metadata(cell, pointer, repairs);
+    repair_source(cell, source_key, pointer, repairs);
+    if cell.get("cell_type").and_then(|t| t.as_str()) == Some("code") {
+        repair_code_cell(cell, is_v3, pointer, repairs);
+        return;
+    }
+    for key in ["outputs", "execution_count", "prompt_number"] {
+        if cell.contains_key(key) {
+            cell.retain(|k, _| k != key);
+            repairs.push(format!("{}: removed {} from a cell that is not a code cell", pointer, key));
+        }
+    }
+}
+
+/// Repair the notebook, returning a description of each repair. Cells that are not objects
+/// are dropped.
+pub fn repair(nb: &mut Value) -> Vec<String> {
+    let mut repairs = Vec::new();
+    let is_v3 = v3::is_v3(nb);
+    let nb = match nb.as_object_mut() {
+        Some(nb) => nb,
+        None => return repairs,
+    };
+    repair_metadata(nb, "/", &mut repairs);
+
+    let mut cell_lists = Vec::new();
+    if nb.get("worksheets").is_some_and(|w| w.is_array()) {
+        let worksheets = nb["worksheets"].as_array_mut().expect("worksheets is an array");
+        for (index, worksheet) in worksheets.iter_mut().enumerate() {
+            if let Some(Value::Array(cells)) = worksheet.get_mut("cells") {
+                cell_lists.push((format!("/worksheets/{}/cells", index), cells));
+            }
+        }
+    } else {
+        let fix = match nb.get("cells") {
+            Some(Value::Array(_)) => None,
+            None => Some("added the missing cells"),
+            Some(_) => Some("replaced cells that were not a list with []"),
+        };
+        if let Some(fix) = fix {
+            nb.insert("cells".to_string(), json!([]));
+            repairs.push(format!("/: {}", fix));
+        }
+        let cells = nb["cells"].as_array_mut().expect("cells was just repaired");
+        cell_lists.push(("/cells".to_string(), cells));
+    }
+
+    // The pointers are to the cells of the notebook before the repairs
+    for (prefix, cells) in cell_lists {
+        for (index, cell) in cells.iter_mut().enumerate() {
+            let pointer = format!("{}/{}", prefix, index);
+            match cell.is_object() {
+                true => repair_cell(cell, is_v3, &pointer, &mut repairs),
+                false => repairs.push(format!("{}: removed a cell that was not an object", pointer)),
+            }
+        }
+        cells.retain(|cell| cell.is_object());
+    }
+
+    for repair in &repairs {
+        log::warn!("Repaired {}", repair);
+    }
+    repairs
+}
@@ -0,0 +1,377 @@
+// Validation of notebooks against the nbformat 4 JSON schemas, embedded from `schema/` as
+// they are in nbformat. The validator implements the parts of JSON schema draft 4 that the
+// nbformat schemas use.
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::OnceLock;
+
+use super::StripError;
+
+const SCHEMAS: [&str; 6] = [
+    include_str!("schema/nbformat.v4.0.schema.json"),
+    include_str!("schema/nbformat.v4.1.schema.json"),
+    include_str!("schema/nbformat.v4.2.schema.json"),
+    include_str!("schema/nbformat.v4.3.schema.json"),
+    include_str!("schema/nbformat.v4.4.schema.json"),
+    include_str!("schema/nbformat.v4.5.schema.json"),
+];
+
+/// The schema for a notebook of this nbformat 4 minor version. Like nbformat, notebooks from
+/// a later minor version than the latest schema are validated against the latest schema.
+fn schema(nbformat_minor: u64) -> &'static Value {
+    static PARSED: OnceLock<Vec<Value>> = OnceLock::new();
+    let schemas = PARSED.get_or_init(|| {
+        SCHEMAS
+            .iter()
+            .map(|schema| serde_json::from_str(schema).expect("the embedded schemas are valid JSON"))
+            .collect()
+    });
+    let minor = (nbformat_minor as usize).min(schemas.len() - 1);
+    &schemas[minor]
+}
+
+/// A way in which a notebook does not match the schema
+#[derive(Debug)]
+pub struct ValidationError {
+    /// JSON pointer to the invalid value, e.g. `/cells/2/outputs/0`
+    pub pointer: String,
+    /// Index of the cell the invalid value is in
+    pub cell: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = if self.pointer.is_empty() { "/" } else { &self.pointer };
+        match self.cell {
+            Some(cell) => write!(f, "{} (cell {}): {}", pointer, cell, self.message),
+            None => write!(f, "{}: {}", pointer, self.message),
+        }
+    }
+}
+
+/// A short representation of a value for error messages, as whole outputs can be large
+fn describe(value: &Value) -> String {
+    let text = match value {
+        Value::String(s) => format!("'{}'", s),
+        _ => value.to_string(),
+    };
+    if text.chars().count() > 60 {
+        format!("{}...", text.chars().take(57).collect::<String>())
+    } else {
+        text
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn is_type(value: &Value, type_name: &str) -> bool {
+    match type_name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    regexes: HashMap<&'a str, Regex>,
+}
+
+impl<'a> Validator<'a> {
+    fn resolve(&self, reference: &str) -> &'a Value {
+        reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .unwrap_or_else(|| panic!("the embedded schema has a broken $ref {}", reference))
+    }
+
+    fn regex(&mut self, pattern: &'a str) -> &Regex {
+        self.regexes.entry(pattern).or_insert_with(|| {
+            Regex::new(pattern).expect("the embedded schema has valid patterns")
+        })
+    }
+
+    /// The branch of a `oneOf` whose `enum` of `cell_type` or `output_type` names the type of
+    /// `value`, to report why it did not match instead of just that no branch did
+    fn discriminated_branch(&self, branches: &'a [Value], value: &Value) -> Option<&'a Value> {
+        branches.iter().map(|branch| self.follow(branch)).find(|branch| {
+            branch
+                .get("properties")
+                .and_then(|p| p.as_object())
+                .is_some_and(|properties| {
+                    properties.iter().any(|(key, property)| {
+                        property
+                            .get("enum")
+                            .and_then(|e| e.as_array())
+                            .zip(value.get(key))
+                            .is_some_and(|(allowed, actual)| allowed.contains(actual))
+                    })
+                })
+        })
+    }
+
+    fn follow(&self, schema: &'a Value) -> &'a Value {
+        match schema.get("$ref").and_then(|r| r.as_str()) {
+            Some(reference) => self.follow(self.resolve(reference)),
+            None => schema,
+        }
+    }
+
+    fn is_valid(&mut self, schema: &'a Value, value: &Value) -> bool {
+        let mut errors = Vec::new();
+        self.validate(schema, value, "", &mut errors);
+        errors.is_empty()
+    }
+
+    fn validate(
+        &mut self,
+        schema: &'a Value,
+        value: &Value,
+        pointer: &str,
+        errors: &mut Vec<(String, String)>,
+    ) {
+        let schema = self.follow(schema);
+        let mut error = |message: String| errors.push((pointer.to_string(), message));
+
+        match schema.get("type") {
+            Some(Value::String(type_name)) if !is_type(value, type_name) => {
+                return error(format!("{} is not of type '{}'", describe(value), type_name));
+            }
+            Some(Value::Array(type_names))
+                if !type_names
+                    .iter()
+                    .filter_map(|t| t.as_str())
+                    .any(|type_name| is_type(value, type_name)) =>
+            {
+                let names: Vec<String> = type_names.iter().map(describe).collect();
+                return error(format!("{} is not of type {}", describe(value), names.join(", ")));
+            }
+            _ => {}
+        }
+        if let Some(allowed) = schema.get("enum").and_then(|e| e.as_array()) {
+            if !allowed.contains(value) {
+                let names: Vec<String> = allowed.iter().map(describe).collect();
+                error(format!("{} is not one of [{}]", describe(value), names.join(", ")));
+            }
+        }
+        if let Some(number) = value.as_f64() {
+            if let Some(minimum) = schema.get("minimum").and_then(|m| m.as_f64()) {
+                if number < minimum {
+                    error(format!("{} is less than the minimum of {}", value, minimum));
+                }
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(|m| m.as_f64()) {
+                if number > maximum {
+                    error(format!("{} is greater than the maximum of {}", value, maximum));
+                }
+            }
+        }
+        if let Some(string) = value.as_str() {
+            let length = string.chars().count() as u64;
+            if let Some(min_length) = schema.get("minLength").and_then(|m| m.as_u64()) {
+                if length < min_length {
+                    error(format!("{} is too short", describe(value)));
+                }
+            }
+            if let Some(max_length) = schema.get("maxLength").and_then(|m| m.as_u64()) {
+                if length > max_length {
+                    error(format!("{} is too long", describe(value)));
+                }
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(|p| p.as_str()) {
+                if !self.regex(pattern).is_match(string) {
+                    error(format!("{} does not match '{}'", describe(value), pattern));
+                }
+            }
+        }
+        if let Some(object) = value.as_object() {
+            self.validate_object(schema, object, pointer, errors);
+        }
+        if let Some(array) = value.as_array() {
+            if let Some(items) = schema.get("items") {
+                for (index, item) in array.iter().enumerate() {
+                    self.validate(items, item, &format!("{}/{}", pointer, index), errors);
+                }
+            }
+            if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+                let has_duplicates = array
+                    .iter()
+                    .enumerate()
+                    .any(|(index, item)| array[..index].contains(item));
+                if has_duplicates {
+                    errors.push((
+                        pointer.to_string(),
+                        format!("{} has non-unique elements", describe(value)),
+                    ));
+                }
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.is_valid(not, value) {
+                errors.push((
+                    pointer.to_string(),
+                    format!("{} should not be valid under {}", describe(value), describe(not)),
+                ));
+            }
+        }
+        if let Some(branches) = schema.get("oneOf").and_then(|o| o.as_array()) {
+            let matching = branches
+                .iter()
+                .filter(|branch| self.is_valid(branch, value))
+                .count();
+            if matching == 0 {
+                match self.discriminated_branch(branches, value) {
+                    Some(branch) => self.validate(branch, value, pointer, errors),
+                    None => errors.push((
+                        pointer.to_string(),
+                        format!("{} is not valid under any of the given schemas", describe(value)),
+                    )),
+                }
+            } else if matching > 1 {
+                errors.push((
+                    pointer.to_string(),
+                    format!("{} is valid under each of the given schemas", describe(value)),
+                ));
+            }
+        }
+    }
+
+    fn validate_object(
+        &mut self,
+        schema: &'a Value,
+        object: &Map<String, Value>,
+        pointer: &str,
+        errors: &mut Vec<(String, String)>,
+    ) {
+        for key in schema
+            .get("required")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|key| key.as_str())
+        {
+            if !object.contains_key(key) {
+                errors.push((pointer.to_string(), format!("'{}' is a required property", key)));
+            }
+        }
+
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        let pattern_properties = schema.get("patternProperties").and_then(|p| p.as_object());
+        let mut unexpected = Vec::new();
+        for (key, value) in object {
+            let child = format!("{}/{}", pointer, escape_pointer(key));
+            let mut matched = false;
+            if let Some(property) = properties.and_then(|p| p.get(key)) {
+                matched = true;
+                self.validate(property, value, &child, errors);
+            }
+            for (pattern, property) in pattern_properties.into_iter().flatten() {
+                if self.regex(pattern).is_match(key) {
+                    matched = true;
+                    self.validate(property, value, &child, errors);
+                }
+            }
+            if matched {
+                continue;
+            }
+            match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => unexpected.push(format!("'{}'", key)),
+                Some(additional @ Value::Object(_)) => {
+                    self.validate(additional, value, &child, errors)
+                }
+                _ => {}
+            }
+        }
+        if !unexpected.is_empty() {
+            let verb = if unexpected.len() == 1 { "was" } else { "were" };
+            errors.push((
+                pointer.to_string(),
+                format!(
+                    "Additional properties are not allowed ({} {} unexpected)",
+                    unexpected.join(", "),
+                    verb
+                ),
+            ));
+        }
+    }
+}
+
+/// The index of the cell that the value at `pointer` is in
+fn cell_index(pointer: &str) -> Option<usize> {
+    pointer
+        .strip_prefix("/cells/")
+        .and_then(|rest| rest.split('/').next())
+        .and_then(|index| index.parse().ok())
+}
+
+/// Validate a notebook against the nbformat 4 schema, like `nbformat.validate`. Cell ids must
+/// also be unique.
+pub fn validate(nb: &Value) -> Result<(), Vec<ValidationError>> {
+    let nbformat = nb.get("nbformat").and_then(|v| v.as_u64());
+    if nbformat.is_some_and(|major| major != 4) {
+        return Err(vec![ValidationError {
+            pointer: "/nbformat".to_string(),
+            cell: None,
+            message: format!(
+                "only nbformat 4 notebooks can be validated, not nbformat {}",
+                nbformat.unwrap_or_default()
+            ),
+        }]);
+    }
+    let nbformat_minor = nb
+        .get("nbformat_minor")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    let schema = schema(nbformat_minor);
+    let mut validator = Validator {
+        root: schema,
+        regexes: HashMap::new(),
+    };
+    let mut errors = Vec::new();
+    validator.validate(schema, nb, "", &mut errors);
+
+    let mut ids = HashSet::new();
+    for (index, cell) in nb
+        .get("cells")
+        .and_then(|c| c.as_array())
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        if let Some(id) = cell.get("id").and_then(|id| id.as_str()) {
+            if !ids.insert(id) {
+                errors.push((format!("/cells/{}/id", index), format!("Non-unique cell id '{}'", id)));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors
+        .into_iter()
+        .map(|(pointer, message)| ValidationError {
+            cell: cell_index(&pointer),
+            pointer,
+            message,
+        })
+        .collect())
+}
+
+/// Validate a notebook, failing with all the errors
+pub fn check(nb: &Value, what: &str) -> Result<(), StripError> {
+    validate(nb).map_err(|violations| StripError {
+        message: format!("{} does not match the nbformat schema", what),
+        violations,
+    })
+}
@@ -0,0 +1,54 @@
+// Whitespace normalisation of cell sources.
+use serde_json::Value;
+
+use super::multiline;
+
+const TAB_WIDTH: usize = 4;
+
+/// Replace tabs in the indentation of a line with spaces
+fn expand_indent_tabs(line: &str) -> String {
+    let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+    let (indent, rest) = line.split_at(indent_len);
+    let mut expanded = String::with_capacity(line.len());
+    for c in indent.chars() {
+        if c == '\t' {
+            let width = TAB_WIDTH - expanded.len() % TAB_WIDTH;
+            expanded.extend(std::iter::repeat_n(' ', width));
+        } else {
+            expanded.push(c);
+        }
+    }
+    expanded + rest
+}
+
+/// Strip trailing whitespace from each line and trailing blank lines from the
+/// source, and optionally replace tabs in indentation with spaces. The source
+/// keeps its representation as a string or list of lines.
+pub fn normalize(source: &mut Value, expand_tabs: bool) {
+    let text = match multiline::join(source) {
+        Some(text) => text,
+        None => return,
+    };
+    let mut lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            let line = line.trim_end();
+            if expand_tabs {
+                expand_indent_tabs(line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    let normalized = lines.join("\n");
+    if normalized != text {
+        *source = match source {
+            Value::Array(_) => multiline::split(&normalized),
+            _ => Value::String(normalized),
+        };
+    }
+}
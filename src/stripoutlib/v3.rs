@@ -0,0 +1,204 @@
+// nbformat 3 notebooks, which keep their cells in `worksheets[].cells`. Code cells
+// keep their source in `input` and their count in `prompt_number`, and outputs use
+// the `pyout` and `pyerr` types, keep their data at the top level under short names
+// (`text`, `png`, ...) and name their stream in `stream`.
+use serde_json::{json, Map, Value};
+
+use super::multiline;
+
+/// Is this an nbformat 3 notebook?
+pub fn is_v3(nb: &Value) -> bool {
+    nb.get("nbformat").and_then(|v| v.as_u64()) == Some(3)
+}
+
+/// The lists of cells in the notebook: the top-level `cells`, or those of each worksheet
+pub fn cell_lists(nb: &mut Value) -> Vec<&mut Vec<Value>> {
+    if nb.get("worksheets").is_some_and(|w| w.is_array()) {
+        return nb["worksheets"]
+            .as_array_mut()
+            .expect("worksheets is an array")
+            .iter_mut()
+            .filter_map(|worksheet| worksheet.get_mut("cells").and_then(|c| c.as_array_mut()))
+            .collect();
+    }
+    nb.get_mut("cells")
+        .and_then(|c| c.as_array_mut())
+        .into_iter()
+        .collect()
+}
+
+/// The key holding the source of a cell: `input` for nbformat 3 code cells
+pub fn source_key(cell: &Value) -> &'static str {
+    if cell.get("source").is_none() && cell.get("input").is_some() {
+        "input"
+    } else {
+        "source"
+    }
+}
+
+/// The nbformat 4 name of an output type
+pub fn v4_output_type(output_type: &str) -> &str {
+    match output_type {
+        "pyout" => "execute_result",
+        "pyerr" => "error",
+        _ => output_type,
+    }
+}
+
+/// The stream an output is written to, if it is a stream output
+pub fn stream_name(output: &Value) -> Option<&str> {
+    output
+        .get("name")
+        .or_else(|| output.get("stream"))
+        .and_then(|n| n.as_str())
+}
+
+/// The `text/plain` data of a display output, which nbformat 3 calls `text`
+pub fn plain_text(output: &Value) -> Option<&Value> {
+    match output.get("data") {
+        Some(data) => data.get("text/plain"),
+        None => output.get("text"),
+    }
+}
+
+fn to_mime_key(key: &str) -> &str {
+    match key {
+        "text" => "text/plain",
+        "html" => "text/html",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpeg" => "image/jpeg",
+        "latex" => "text/latex",
+        "json" => "application/json",
+        "javascript" => "application/javascript",
+        _ => key,
+    }
+}
+
+fn upgrade_output(output: &mut Map<String, Value>) {
+    let output_type = output
+        .get("output_type")
+        .and_then(|t| t.as_str())
+        .unwrap_or("")
+        .to_string();
+    match output_type.as_str() {
+        "pyout" | "display_data" => {
+            let metadata = match output.remove("metadata") {
+                Some(Value::Object(metadata)) => metadata
+                    .into_iter()
+                    .map(|(key, value)| (to_mime_key(&key).to_string(), value))
+                    .collect(),
+                _ => Map::new(),
+            };
+            // Only `pyout` outputs have a count. nbformat moves the `prompt_number` of a
+            // `display_data` output into its data, which is then not valid, so it is dropped.
+            let execution_count = output.remove("prompt_number");
+            let mut data = Map::new();
+            for (key, mut value) in std::mem::take(output) {
+                if key == "output_type" {
+                    continue;
+                }
+                let key = to_mime_key(&key);
+                // nbformat 3 stores JSON data as a string
+                if key == "application/json" {
+                    if let Some(parsed) = value.as_str().and_then(|s| serde_json::from_str(s).ok()) {
+                        value = parsed;
+                    }
+                }
+                data.insert(key.to_string(), value);
+            }
+            output.insert("output_type".to_string(), json!(v4_output_type(&output_type)));
+            if output_type == "pyout" {
+                output.insert(
+                    "execution_count".to_string(),
+                    execution_count.unwrap_or(Value::Null),
+                );
+            }
+            output.insert("data".to_string(), Value::Object(data));
+            output.insert("metadata".to_string(), Value::Object(metadata));
+        }
+        "pyerr" => {
+            output.insert("output_type".to_string(), json!("error"));
+        }
+        "stream" => {
+            let name = output.remove("stream").unwrap_or_else(|| json!("stdout"));
+            output.insert("name".to_string(), name);
+        }
+        _ => {}
+    }
+}
+
+fn upgrade_cell(cell: &mut Map<String, Value>) {
+    if !cell.get("metadata").is_some_and(|m| m.is_object()) {
+        cell.insert("metadata".to_string(), json!({}));
+    }
+    match cell.get("cell_type").and_then(|t| t.as_str()) {
+        Some("code") => {
+            cell.remove("language");
+            if let Some(collapsed) = cell.remove("collapsed") {
+                cell["metadata"]["collapsed"] = collapsed;
+            }
+            let source = cell.remove("input").unwrap_or_else(|| json!(""));
+            cell.insert("source".to_string(), source);
+            let execution_count = cell.remove("prompt_number").unwrap_or(Value::Null);
+            cell.insert("execution_count".to_string(), execution_count);
+            if let Some(outputs) = cell.get_mut("outputs").and_then(|o| o.as_array_mut()) {
+                for output in outputs.iter_mut().filter_map(|o| o.as_object_mut()) {
+                    upgrade_output(output);
+                }
+            }
+        }
+        Some("heading") => {
+            // Headings become a single line of markdown, as nbformat does
+            let level = cell.remove("level").and_then(|l| l.as_u64()).unwrap_or(1);
+            let source = cell
+                .get("source")
+                .and_then(multiline::join)
+                .unwrap_or_default();
+            let single_line = source.lines().collect::<Vec<_>>().join(" ");
+            cell.insert("cell_type".to_string(), json!("markdown"));
+            cell.insert(
+                "source".to_string(),
+                json!(format!("{} {}", "#".repeat(level as usize), single_line)),
+            );
+        }
+        Some("html") => {
+            cell.insert("cell_type".to_string(), json!("markdown"));
+        }
+        _ => {}
+    }
+}
+
+/// Upgrade an nbformat 3 notebook to nbformat 4, like `nbformat.v4.upgrade`
+pub fn upgrade(nb: &mut Value) {
+    let nb = match nb.as_object_mut() {
+        Some(nb) => nb,
+        None => return,
+    };
+    let mut cells = Vec::new();
+    if let Some(Value::Array(worksheets)) = nb.remove("worksheets") {
+        for worksheet in worksheets {
+            if let Value::Object(mut worksheet) = worksheet {
+                if let Some(Value::Array(worksheet_cells)) = worksheet.remove("cells") {
+                    cells.extend(worksheet_cells);
+                }
+            }
+        }
+    }
+    for cell in cells.iter_mut().filter_map(|c| c.as_object_mut()) {
+        upgrade_cell(cell);
+    }
+    nb.insert("cells".to_string(), Value::Array(cells));
+
+    if !nb.get("metadata").is_some_and(|m| m.is_object()) {
+        nb.insert("metadata".to_string(), json!({}));
+    }
+    let metadata = nb["metadata"].as_object_mut().expect("metadata was just set");
+    metadata.remove("name");
+    metadata.remove("signature");
+    metadata.insert("orig_nbformat".to_string(), json!(3));
+
+    nb.insert("nbformat".to_string(), json!(4));
+    // Minor version 5 requires cell ids, which nbformat 3 cells do not have
+    nb.insert("nbformat_minor".to_string(), json!(4));
+}
@@ -0,0 +1,142 @@
+// Handling of ipywidgets outputs and the widget state stored in the notebook metadata.
+//
+// Widget outputs only hold a reference (`model_id`) to a model in
+// `metadata.widgets["application/vnd.jupyter.widget-state+json"].state`, and
+// models may in turn reference other models as `"IPY_MODEL_<id>"` strings.
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+const WIDGET_STATE_MIMETYPE: &str = "application/vnd.jupyter.widget-state+json";
+const WIDGET_VIEW_MIMETYPE: &str = "application/vnd.jupyter.widget-view+json";
+const MODEL_REFERENCE_PREFIX: &str = "IPY_MODEL_";
+
+/// What to do with widget outputs and the stored widget state
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WidgetPolicy {
+    /// Leave widget outputs and state alone
+    #[default]
+    Keep,
+    /// Drop widget outputs whose model is not in the (stripped) widget state
+    DropOrphans,
+    /// Only keep the widget state used by kept widget outputs
+    PruneState,
+    /// Replace widget outputs with their `text/plain` fallback
+    TextFallback,
+}
+
+impl FromStr for WidgetPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(WidgetPolicy::Keep),
+            "drop_orphans" => Ok(WidgetPolicy::DropOrphans),
+            "prune_state" => Ok(WidgetPolicy::PruneState),
+            "text_fallback" => Ok(WidgetPolicy::TextFallback),
+            _ => Err(format!(
+                "'{}' is not a valid widget policy, must be one of keep, drop_orphans, prune_state or text_fallback",
+                s
+            )),
+        }
+    }
+}
+
+/// The widget models stored in the notebook metadata, if any
+fn widget_state(metadata: Option<&Value>) -> Option<&serde_json::Map<String, Value>> {
+    metadata?
+        .get("widgets")?
+        .get(WIDGET_STATE_MIMETYPE)?
+        .get("state")?
+        .as_object()
+}
+
+/// The model shown by a widget output, if it is one
+fn view_model_id(output: &Value) -> Option<&str> {
+    output
+        .get("data")?
+        .get(WIDGET_VIEW_MIMETYPE)?
+        .get("model_id")?
+        .as_str()
+}
+
+/// Ids of all models stored in the notebook metadata
+pub fn stored_model_ids(metadata: Option<&Value>) -> HashSet<String> {
+    widget_state(metadata)
+        .map(|state| state.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Apply `policy` to the (kept) outputs of a cell, recording the models still in use
+pub fn apply_to_outputs(
+    outputs: &mut Vec<Value>,
+    policy: WidgetPolicy,
+    stored: &HashSet<String>,
+    used: &mut HashSet<String>,
+) {
+    match policy {
+        WidgetPolicy::Keep => {}
+        WidgetPolicy::DropOrphans => outputs.retain(|output| match view_model_id(output) {
+            Some(model_id) if !stored.contains(model_id) => {
+                log::debug!("Dropping output of missing widget {}", model_id);
+                false
+            }
+            _ => true,
+        }),
+        WidgetPolicy::PruneState => {
+            used.extend(outputs.iter().filter_map(view_model_id).map(String::from));
+        }
+        WidgetPolicy::TextFallback => outputs.retain_mut(|output| {
+            let data = match output.get_mut("data").and_then(|d| d.as_object_mut()) {
+                Some(data) => data,
+                None => return true,
+            };
+            if data.remove(WIDGET_VIEW_MIMETYPE).is_none() {
+                return true;
+            }
+            // Without a fallback there is nothing left to show
+            !data.is_empty()
+        }),
+    }
+}
+
+/// Every string in `value` that references another model
+fn referenced_models(value: &Value, found: &mut Vec<String>) {
+    match value {
+        Value::String(s) => {
+            if let Some(model_id) = s.strip_prefix(MODEL_REFERENCE_PREFIX) {
+                found.push(model_id.to_string());
+            }
+        }
+        Value::Array(arr) => arr.iter().for_each(|v| referenced_models(v, found)),
+        Value::Object(obj) => obj.values().for_each(|v| referenced_models(v, found)),
+        _ => {}
+    }
+}
+
+/// Remove all widget models from the notebook metadata that are not used by `used`
+/// or by the models they (transitively) reference
+pub fn prune_state(metadata: Option<&mut Value>, used: &HashSet<String>) {
+    let state = match metadata
+        .and_then(|m| m.get_mut("widgets"))
+        .and_then(|w| w.get_mut(WIDGET_STATE_MIMETYPE))
+        .and_then(|w| w.get_mut("state"))
+        .and_then(|s| s.as_object_mut())
+    {
+        Some(state) => state,
+        None => return,
+    };
+
+    let mut keep: HashSet<String> = HashSet::new();
+    let mut pending: Vec<String> = used.iter().cloned().collect();
+    while let Some(model_id) = pending.pop() {
+        if let Some(model) = state.get(&model_id) {
+            if keep.insert(model_id) {
+                referenced_models(model, &mut pending);
+            }
+        }
+    }
+    state.retain(|model_id, _| keep.contains(model_id));
+}
@@ -0,0 +1,88 @@
+// Zeppelin notes, which hold a list of `paragraphs`, each with its `text`, the
+// `results` of running it (`result` before Zeppelin 0.8) and run-time information.
+use regex::Regex;
+use serde_json::{json, Value};
+
+/// Keys of a paragraph that change every time it is run
+const RUNTIME_KEYS: [&str; 6] = [
+    "dateStarted",
+    "dateFinished",
+    "dateUpdated",
+    "runtimeInfos",
+    "progress",
+    "status",
+];
+
+/// Does this look like a Zeppelin note rather than a Jupyter notebook?
+pub fn is_note(nb: &Value) -> bool {
+    nb.get("paragraphs").is_some_and(|p| p.is_array())
+        && nb.get("cells").is_none()
+        && nb.get("worksheets").is_none()
+}
+
+fn is_empty(paragraph: &Value) -> bool {
+    paragraph
+        .get("text")
+        .and_then(|t| t.as_str())
+        .is_none_or(|text| text.trim().is_empty())
+}
+
+/// Strip a Zeppelin note. Results are kept with `keep_output`, except for the
+/// messages matching `strip_regex`.
+pub fn strip(
+    note: &mut Value,
+    keep_output: bool,
+    strip_regex: Option<&Regex>,
+    drop_empty_paragraphs: bool,
+) -> Result<(), String> {
+    let paragraphs = note
+        .get_mut("paragraphs")
+        .and_then(|p| p.as_array_mut())
+        .ok_or("Zeppelin note does not contain a list of paragraphs; note is malformed.")?;
+
+    if drop_empty_paragraphs {
+        paragraphs.retain(|paragraph| !is_empty(paragraph));
+    }
+
+    for paragraph in paragraphs.iter_mut() {
+        let paragraph = paragraph
+            .as_object_mut()
+            .ok_or("Zeppelin paragraph is not a JSON object; note is malformed.")?;
+        // `retain` rather than `remove`, which would reorder the keys
+        paragraph.retain(|key, _| !RUNTIME_KEYS.contains(&key.as_str()));
+
+        if !keep_output {
+            if paragraph.contains_key("results") {
+                // Cleared like nbstripout does
+                paragraph.insert("results".to_string(), json!({}));
+            }
+            paragraph.retain(|key, _| key != "result");
+            continue;
+        }
+        let strip_regex = match strip_regex {
+            Some(reg) => reg,
+            None => continue,
+        };
+        if let Some(msg) = paragraph
+            .get_mut("results")
+            .and_then(|r| r.get_mut("msg"))
+            .and_then(|m| m.as_array_mut())
+        {
+            msg.retain(|message| {
+                !message
+                    .get("data")
+                    .and_then(|d| d.as_str())
+                    .is_some_and(|data| strip_regex.is_match(data))
+            });
+        }
+        if paragraph
+            .get("result")
+            .and_then(|r| r.get("msg"))
+            .and_then(|m| m.as_str())
+            .is_some_and(|msg| strip_regex.is_match(msg))
+        {
+            paragraph.retain(|key, _| key != "result");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+// Tests of the nbstripout-fast binary, which the python tests can not run
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+use std::{env, fs};
+
+const NOTEBOOK: &str = include_str!("test_notebook.ipynb");
+
+/// Run nbstripout-fast with `args`, writing `stdin` to it
+fn run(args: &[&str], stdin: &[u8]) -> Output {
+    run_in(Path::new(env!("CARGO_MANIFEST_DIR")), args, stdin)
+}
+
+/// Run nbstripout-fast in `dir`
+fn run_in(dir: &Path, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nbstripout-fast"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("nbstripout-fast runs");
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(stdin)
+        .expect("nbstripout-fast reads STDIN");
+    child.wait_with_output().expect("nbstripout-fast exits")
+}
+
+/// A new directory that looks like a git repository, with this `.git-nbconfig.yaml`
+fn repository(name: &str, nbconfig: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nbstripout-fast-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join(".git")).unwrap();
+    fs::write(dir.join(".git-nbconfig.yaml"), nbconfig).unwrap();
+    dir
+}
+
+fn execution_count(stdout: &[u8]) -> Value {
+    let nb: Value = serde_json::from_slice(stdout).unwrap();
+    nb["cells"][0]["execution_count"].clone()
+}
+
+fn request(path: &str, contents: &str) -> Vec<u8> {
+    serde_json::to_vec(&json!({"path": path, "contents": contents})).unwrap()
+}
+
+fn ndjson(frames: &[Vec<u8>]) -> Vec<u8> {
+    frames
+        .iter()
+        .flat_map(|frame| [frame.as_slice(), b"\n"].concat())
+        .collect()
+}
+
+fn length_prefixed(frames: &[Vec<u8>]) -> Vec<u8> {
+    frames
+        .iter()
+        .flat_map(|frame| [format!("{}\n", frame.len()).as_bytes(), frame].concat())
+        .collect()
+}
+
+fn parse_ndjson(stdout: &[u8]) -> Vec<Value> {
+    stdout
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect()
+}
+
+fn parse_length_prefixed(mut stdout: &[u8]) -> Vec<Value> {
+    let mut responses = Vec::new();
+    while !stdout.is_empty() {
+        let newline = stdout.iter().position(|&b| b == b'\n').unwrap();
+        let length: usize = std::str::from_utf8(&stdout[..newline])
+            .unwrap()
+            .parse()
+            .unwrap();
+        let frame = &stdout[newline + 1..newline + 1 + length];
+        responses.push(serde_json::from_slice(frame).unwrap());
+        stdout = &stdout[newline + 1 + length..];
+    }
+    responses
+}
+
+fn stripped_notebook() -> String {
+    let output = run(&[], NOTEBOOK.as_bytes());
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn batch_ndjson() {
+    let stripped = stripped_notebook();
+    let output = run(
+        &["--batch"],
+        &ndjson(&[request("a.ipynb", NOTEBOOK), request("b.ipynb", &stripped)]),
+    );
+
+    assert!(output.status.success());
+    let responses = parse_ndjson(&output.stdout);
+    assert_eq!(
+        responses,
+        [
+            json!({"path": "a.ipynb", "contents": stripped, "changed": true}),
+            json!({"path": "b.ipynb", "contents": stripped, "changed": false}),
+        ]
+    );
+}
+
+#[test]
+fn batch_length_prefixed() {
+    let stripped = stripped_notebook();
+    let output = run(
+        &["--batch", "--batch-format", "length_prefixed"],
+        &length_prefixed(&[request("a.ipynb", NOTEBOOK), request("b.ipynb", &stripped)]),
+    );
+
+    assert!(output.status.success());
+    let responses = parse_length_prefixed(&output.stdout);
+    assert_eq!(
+        responses,
+        [
+            json!({"path": "a.ipynb", "contents": stripped, "changed": true}),
+            json!({"path": "b.ipynb", "contents": stripped, "changed": false}),
+        ]
+    );
+}
+
+#[test]
+fn batch_bad_frame_then_good_frame() {
+    let output = run(
+        &["--batch"],
+        &ndjson(&[
+            b"not json".to_vec(),
+            request("bad.ipynb", "not a notebook"),
+            request("a.ipynb", NOTEBOOK),
+        ]),
+    );
+
+    assert!(output.status.success());
+    let responses = parse_ndjson(&output.stdout);
+    assert_eq!(responses.len(), 3);
+    assert!(responses[0]["error"]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Batch request is not valid"));
+    assert_eq!(responses[1]["path"], "bad.ipynb");
+    assert!(responses[1]["error"]["message"].is_string());
+    assert!(responses[1].get("contents").is_none());
+    assert_eq!(responses[2]["path"], "a.ipynb");
+    assert_eq!(responses[2]["changed"], true);
+}
+
+#[test]
+fn batch_validation_error() {
+    let mut nb: Value = serde_json::from_str(NOTEBOOK).unwrap();
+    nb["cells"][1]["execution_count"] = json!(-1);
+    let output = run(
+        &["--batch", "--validate"],
+        &ndjson(&[request("a.ipynb", &nb.to_string())]),
+    );
+
+    assert!(output.status.success());
+    let responses = parse_ndjson(&output.stdout);
+    let error = &responses[0]["error"];
+    assert_eq!(error["pointer"], "/cells/1/execution_count");
+    assert_eq!(error["cell"], 1);
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("The notebook does not match the nbformat schema"));
+}
+
+#[test]
+fn batch_frame_too_large() {
+    let output = run(
+        &["--batch", "--batch-format", "length_prefixed"],
+        b"99999999999999999\n{}",
+    );
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("larger than the maximum"));
+}
+
+#[test]
+fn batch_frame_truncated() {
+    let output = run(&["--batch", "--batch-format", "length_prefixed"], b"10\n{}");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("STDIN ended"));
+}
+
+#[test]
+fn ignore_git_nb_config() {
+    let dir = repository("ignore", "nbstripout_fast:\n  keep_count: true\n");
+
+    let output = run_in(&dir, &[], NOTEBOOK.as_bytes());
+    assert!(output.status.success());
+    assert_eq!(execution_count(&output.stdout), 21);
+
+    let output = run_in(&dir, &["--ignore-git-nb-config"], NOTEBOOK.as_bytes());
+    assert!(output.status.success());
+    assert_eq!(execution_count(&output.stdout), Value::Null);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn stdin_unchanged_is_written() {
+    let stripped = stripped_notebook();
+
+    let output = run(&[], stripped.as_bytes());
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), stripped);
+}
+
+#[test]
+fn stdin_byte_for_byte() {
+    let stripped = stripped_notebook();
+    let crlf = stripped.replace('\n', "\r\n");
+    let bom = format!("\u{feff}{}", stripped);
+    for contents in [stripped.trim_end(), &crlf, &bom] {
+        let output = run(&[], contents.as_bytes());
+        assert!(output.status.success());
+        assert_eq!(output.stdout, contents.as_bytes());
+    }
+}
+
+#[test]
+fn stdin_file_path_finds_nbconfig() {
+    let dir = repository("stdin-file-path", "nbstripout_fast:\n  keep_count: true\n");
+    fs::create_dir_all(dir.join("notebooks")).unwrap();
+    let name = dir.file_name().unwrap().to_str().unwrap();
+    let path = format!("{}/notebooks/a.ipynb", name);
+
+    // Relative to the current directory, like git's %f
+    let output = run_in(
+        dir.parent().unwrap(),
+        &["--stdin-file-path", &path],
+        NOTEBOOK.as_bytes(),
+    );
+    assert!(output.status.success());
+    assert_eq!(execution_count(&output.stdout), 21);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,4 +1,6 @@
 import json
+import pathlib
+import re
 from copy import deepcopy
 
 import nbformat
@@ -6,7 +8,9 @@ import pytest
 from nbconvert.preprocessors import ExecutePreprocessor
 from nbstripout_fast import stripout
 
-# Copied from main.rs
+GOLDEN_DIR = pathlib.Path(__file__).parent / "golden"
+
+# Copied from stripoutlib.rs
 DEFAULT_EXTRA_KEYS = [
     "metadata.signature",
     "metadata.widgets",
@@ -56,6 +60,7 @@ def _stripout_helper(
     extra_keys=None,
     drop_empty_cells=False,
     strip_regex=None,
+    **settings,
 ):
     if extra_keys is None:
         extra_keys = DEFAULT_EXTRA_KEYS
@@ -66,6 +71,7 @@ def _stripout_helper(
         extra_keys=extra_keys,
         drop_empty_cells=drop_empty_cells,
         strip_regex=strip_regex,
+        settings=settings,
     )
     return nbformat.v4.reads(content)
 
@@ -118,6 +124,21 @@ def test_drop_empty_cells():
     assert copied_nb == stripped_notebook
 
 
+def test_normalize_source():
+    nb = deepcopy(clean_nb)
+    nb.cells[0].source = "# Welcome to my notebook  \n\n"
+    nb.cells[1].source = "if True:  \n\tx = 1\t"
+    nb.cells[5].source = "x += 3\nx\n\n  \n\t\n"
+
+    stripped_notebook = _stripout_helper(nb, normalize_source=["code"])
+
+    # Only code cells are normalised...
+    assert stripped_notebook.cells[0].source == "# Welcome to my notebook  \n\n"
+    assert stripped_notebook.cells[1].source == "if True:\n    x = 1"
+    # ...including their trailing blank lines
+    assert stripped_notebook.cells[5].source == "x += 3\nx"
+
+
 def test_keep_output_tag():
     copied_executed_nb = deepcopy(executed_nb)
     copied_executed_nb.cells[1]["metadata"]["tags"] = ["keep_output"]
@@ -128,6 +149,86 @@ def test_keep_output_tag():
     assert sum(len(cell.get("outputs", [])) for cell in stripped_notebook.cells) == 1
 
 
+def test_strip_output_tag():
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.cells[1]["metadata"]["tags"] = ["strip_output"]
+    stripped_notebook = _stripout_helper(copied_executed_nb, keep_output=True)
+
+    assert len(stripped_notebook.cells[1]["outputs"]) == 0
+    assert len(stripped_notebook.cells[2]["outputs"]) == 1
+
+
+def test_keep_and_strip_output_tags_contradict():
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.cells[1]["metadata"]["tags"] = ["strip_output"]
+    copied_executed_nb.cells[1]["metadata"]["keep_output"] = True
+
+    with pytest.raises(RuntimeError):
+        _stripout_helper(copied_executed_nb)
+
+
+def test_configured_tags():
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.cells[1]["metadata"]["tags"] = ["show", "count"]
+    copied_executed_nb.cells[2]["metadata"]["hide"] = True
+    stripped_notebook = _stripout_helper(
+        copied_executed_nb,
+        tags={"keep_output": "show", "strip_output": "hide", "keep_count": "count"},
+    )
+
+    assert len(stripped_notebook.cells[1]["outputs"]) == 1
+    assert stripped_notebook.cells[1]["execution_count"] == 1
+    assert stripped_notebook.cells[1]["outputs"][0]["execution_count"] == 1
+    assert len(stripped_notebook.cells[2]["outputs"]) == 0
+    assert stripped_notebook.cells[2]["execution_count"] is None
+
+
+@pytest.mark.parametrize("strip_hidden_outputs", [True, False])
+def test_strip_hidden_outputs(strip_hidden_outputs):
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.cells[1]["metadata"]["jupyter"] = {"outputs_hidden": True}
+    stripped_notebook = _stripout_helper(
+        copied_executed_nb,
+        keep_output=True,
+        strip_hidden_outputs=strip_hidden_outputs,
+    )
+
+    assert len(stripped_notebook.cells[1]["outputs"]) == (0 if strip_hidden_outputs else 1)
+
+
+def test_strip_hidden_outputs_keep_output_tag():
+    # An explicit keep_output wins over collapsed outputs
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.cells[1]["metadata"]["jupyter"] = {"outputs_hidden": True}
+    copied_executed_nb.cells[1]["metadata"]["tags"] = ["keep_output"]
+    copied_executed_nb.cells[2]["metadata"]["jupyter"] = {"outputs_hidden": True}
+    stripped_notebook = _stripout_helper(copied_executed_nb, strip_hidden_outputs=True)
+
+    assert len(stripped_notebook.cells[1]["outputs"]) == 1
+    assert len(stripped_notebook.cells[2]["outputs"]) == 0
+
+
+def test_configured_notebook_keep_output():
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.metadata["show"] = True
+    stripped_notebook = _stripout_helper(copied_executed_nb, tags={"keep_output": "show"})
+
+    assert len(stripped_notebook.cells[1]["outputs"]) == 1
+
+
+def test_cell_keep_keys():
+    copied_executed_nb = deepcopy(executed_nb)
+    copied_executed_nb.cells[1]["metadata"]["collapsed"] = True
+    copied_executed_nb.cells[1]["metadata"]["nbstripout"] = {
+        "keep_keys": ["cell.metadata.collapsed"]
+    }
+    copied_executed_nb.cells[2]["metadata"]["collapsed"] = True
+    stripped_notebook = _stripout_helper(copied_executed_nb)
+
+    assert stripped_notebook.cells[1]["metadata"]["collapsed"] is True
+    assert "collapsed" not in stripped_notebook.cells[2]["metadata"]
+
+
 def test_extra_keys():
     stripped_notebook = _stripout_helper(
         executed_nb, extra_keys=DEFAULT_EXTRA_KEYS + ["cell.id"]
@@ -136,6 +237,507 @@ def test_extra_keys():
     assert all("id" not in cell for cell in stripped_notebook.cells)
 
 
+def test_extra_keys_wildcards():
+    nb = deepcopy(clean_nb)
+    nb.metadata["a"] = {"b": {"name": 1}, "name": 2, "other": 3}
+    nb.cells[1].metadata["jupyter"] = {"source_hidden": True, "outputs_hidden": True}
+
+    stripped_notebook = _stripout_helper(
+        nb, extra_keys=["metadata.**.name", "cell.metadata.jupyter.*"]
+    )
+
+    assert stripped_notebook.metadata["a"] == {"b": {}, "other": 3}
+    assert stripped_notebook.cells[1].metadata["jupyter"] == {}
+
+
+def test_keep_keys_carve_out():
+    nb = deepcopy(clean_nb)
+    nb.cells[1].metadata["jupyter"] = {"source_hidden": True, "outputs_hidden": True}
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        extra_keys=["cell.metadata.jupyter", "metadata.**"],
+        keep_keys=["cell.metadata.jupyter.source_hidden", "metadata.kernelspec.name"],
+    )
+
+    assert stripped_notebook.cells[1].metadata["jupyter"] == {"source_hidden": True}
+    assert stripped_notebook.metadata == {"kernelspec": {"name": "python3"}}
+
+
+def test_keep_keys_wildcard_strips_the_rest():
+    nb = deepcopy(clean_nb)
+    nb.metadata["foo"] = {"x": 1, "name": "foo"}
+    nb.metadata["bar"] = 5
+    nb.cells[1].metadata["collapsed"] = True
+    nb.cells[1].metadata["ExecuteTime"] = {"start_time": "2020-01-01"}
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        extra_keys=DEFAULT_EXTRA_KEYS + ["metadata.foo", "metadata.bar"],
+        keep_keys=["metadata.**.name", "cell.metadata.**.foo"],
+    )
+
+    assert stripped_notebook.metadata["foo"] == {"name": "foo"}
+    assert "bar" not in stripped_notebook.metadata
+    assert stripped_notebook.cells[1].metadata == {}
+
+
+def test_extra_keys_quoted_and_indexed():
+    nb = deepcopy(clean_nb)
+    nb.metadata["jupyterlab.toc"] = {"number_sections": True}
+    nb.metadata["jupyterlab"] = {"toc": {"keep": True}}
+    nb.cells[1].metadata["tags"] = ["first", "second"]
+
+    stripped_notebook = _stripout_helper(
+        nb, extra_keys=['metadata."jupyterlab.toc"', "cell.metadata.tags[0]"]
+    )
+
+    assert "jupyterlab.toc" not in stripped_notebook.metadata
+    assert stripped_notebook.metadata["jupyterlab"] == {"toc": {"keep": True}}
+    assert stripped_notebook.cells[1].metadata["tags"] == ["second"]
+
+
+def test_keep_keys_wildcard_quoted_and_indexed():
+    nb = deepcopy(clean_nb)
+    nb.metadata["jupyterlab.toc"] = {
+        "name": "toc",
+        "number_sections": True,
+        "items": [1, {"name": "a", "level": 2}],
+    }
+    nb.cells[1].metadata["tags"] = ["first", {"name": "second"}]
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        extra_keys=['metadata."jupyterlab.toc"', "cell.metadata.tags[*]"],
+        keep_keys=["metadata.**.name", "cell.metadata.**.name"],
+    )
+
+    assert stripped_notebook.metadata["jupyterlab.toc"] == {
+        "name": "toc",
+        "items": [{"name": "a"}],
+    }
+    assert stripped_notebook.cells[1].metadata["tags"] == [{"name": "second"}]
+
+
+def test_keep_keys_array_wildcard():
+    nb = deepcopy(clean_nb)
+    nb.cells[1].metadata["tags"] = ["first", "second", "third"]
+
+    stripped_notebook = _stripout_helper(
+        nb, extra_keys=["cell.metadata.tags[*]"], keep_keys=["cell.metadata.tags[1]"]
+    )
+
+    assert stripped_notebook.cells[1].metadata["tags"] == ["second"]
+
+
+@pytest.mark.parametrize(
+    "key", ['metadata."unterminated', "cell.metadata.tags[x]", "cell..foo", "cell"]
+)
+def test_invalid_keys(key):
+    with pytest.raises(RuntimeError):
+        _stripout_helper(executed_nb, extra_keys=[key])
+
+
+def test_output_keys():
+    stripped_notebook = _stripout_helper(
+        executed_nb,
+        keep_output=True,
+        extra_keys=DEFAULT_EXTRA_KEYS + ["output.metadata", "output:stream.name"],
+    )
+
+    outputs = [out for cell in stripped_notebook.cells for out in cell.get("outputs", [])]
+    assert outputs
+    assert all("metadata" not in out for out in outputs)
+    assert all("name" not in out for out in outputs if out["output_type"] == "stream")
+
+
+def test_output_keys_filtered_by_type():
+    nb = deepcopy(clean_nb)
+    nb.cells[1].outputs = [
+        nbformat.v4.new_output(
+            "display_data",
+            data={"text/plain": "<Figure>"},
+            metadata={"needs_background": "light"},
+        ),
+        nbformat.v4.new_output(
+            "execute_result",
+            data={"text/plain": "2"},
+            metadata={"needs_background": "light"},
+        ),
+    ]
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        keep_output=True,
+        extra_keys=["output:display_data.metadata.needs_background"],
+    )
+
+    outputs = stripped_notebook.cells[1].outputs
+    assert outputs[0].metadata == {}
+    assert outputs[1].metadata == {"needs_background": "light"}
+
+
+def test_cell_type_keys():
+    nb = deepcopy(clean_nb)
+    nb.cells.append(
+        nbformat.v4.new_raw_cell("raw", metadata={"raw_mimetype": "text/x-rst"})
+    )
+    for cell in nb.cells:
+        cell.metadata["tags"] = ["a-tag"]
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        extra_keys=["code.metadata.tags", "raw.metadata.*"],
+        keep_keys=["raw.metadata.tags"],
+    )
+
+    for cell in stripped_notebook.cells:
+        if cell.cell_type == "code":
+            assert cell.metadata == {}
+        else:
+            assert cell.metadata == {"tags": ["a-tag"]}
+
+
+def test_keep_only_metadata():
+    nb = deepcopy(clean_nb)
+    nb.metadata["language_info"] = {"name": "python", "version": "3.11.4"}
+    nb.metadata["colab"] = {"provenance": []}
+    nb.cells[1].metadata.update(
+        {"tags": ["a-tag"], "slideshow": {"slide_type": "slide"}, "vscode": {}}
+    )
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        keep_only_metadata=["kernelspec", "language_info.name"],
+        keep_only_cell_metadata=["tags", "slideshow"],
+    )
+
+    assert stripped_notebook.metadata == {
+        "kernelspec": nb.metadata["kernelspec"],
+        "language_info": {"name": "python"},
+    }
+    assert stripped_notebook.cells[1].metadata == {
+        "tags": ["a-tag"],
+        "slideshow": {"slide_type": "slide"},
+    }
+
+
+def test_keep_only_metadata_with_extra_keys():
+    nb = deepcopy(clean_nb)
+    nb.cells[1].metadata.update({"tags": ["a-tag"], "collapsed": True})
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        extra_keys=DEFAULT_EXTRA_KEYS + ["metadata.kernelspec.display_name"],
+        keep_only_metadata=["kernelspec"],
+        keep_only_cell_metadata=["collapsed"],
+        keep_keys=["cell.metadata.tags"],
+    )
+
+    assert stripped_notebook.metadata == {
+        "kernelspec": {"language": "python", "name": "python3"}
+    }
+    # collapsed is still stripped by the default extra keys, tags by keep_keys
+    assert stripped_notebook.cells[1].metadata == {"tags": ["a-tag"]}
+
+
+def test_normalize_outputs():
+    nb = deepcopy(clean_nb)
+    nb.cells[1].outputs = [
+        nbformat.v4.new_output("stream", name="stdout", text="a\nb"),
+        nbformat.v4.new_output("stream", name="stdout", text="c\n"),
+        nbformat.v4.new_output("stream", name="stderr", text=""),
+        nbformat.v4.new_output("display_data", data={}),
+        nbformat.v4.new_output("stream", name="stderr", text="warning\n"),
+        nbformat.v4.new_output("stream", name="stdout", text="d\n"),
+    ]
+
+    stripped_notebook = _stripout_helper(nb, keep_output=True, normalize_outputs=True)
+
+    assert stripped_notebook.cells[1].outputs == [
+        nbformat.v4.new_output("stream", name="stdout", text="a\nbc\n"),
+        nbformat.v4.new_output("stream", name="stderr", text="warning\n"),
+        nbformat.v4.new_output("stream", name="stdout", text="d\n"),
+    ]
+
+
+def test_kernelspec_language_info():
+    extra_keys = [key for key in DEFAULT_EXTRA_KEYS if key != "metadata.language_info"]
+    stripped_notebook = _stripout_helper(
+        executed_nb,
+        extra_keys=extra_keys,
+        kernelspec={"language_info": ["name", "pygments_lexer"]},
+    )
+
+    assert stripped_notebook.metadata["language_info"] == {
+        "name": "python",
+        "pygments_lexer": "ipython3",
+    }
+
+
+@pytest.mark.parametrize(
+    ("kernelspec", "expected"),
+    [
+        ({}, {"name": "conda-env-foo-py", "display_name": "Python [conda env:foo]"}),
+        ({"name": "python3"}, {"name": "python3", "display_name": "Python [conda env:foo]"}),
+        (
+            {
+                "display_name": "Python 3",
+                "aliases": {"conda-env-foo-py": "python3", "conda-env-bar-py": "other"},
+            },
+            {"name": "python3", "display_name": "Python 3"},
+        ),
+    ],
+)
+def test_kernelspec(kernelspec, expected):
+    nb = deepcopy(clean_nb)
+    nb.metadata.kernelspec = {
+        "display_name": "Python [conda env:foo]",
+        "language": "python",
+        "name": "conda-env-foo-py",
+    }
+
+    stripped_notebook = _stripout_helper(nb, kernelspec=kernelspec)
+
+    assert stripped_notebook.metadata.kernelspec == dict(expected, language="python")
+
+
+def _papermill_notebook():
+    nb = deepcopy(executed_nb)
+    nb.metadata["papermill"] = {"parameters": {"x": 3}, "duration": 1.5}
+    for cell in nb.cells:
+        cell.metadata["papermill"] = {"duration": 0.1, "status": "completed"}
+    nb.cells[2].metadata["tags"] = ["parameters"]
+    injected = nbformat.v4.new_code_cell("x = 3", metadata={"tags": ["injected-parameters"]})
+    nb.cells.insert(3, injected)
+    return nb
+
+
+@pytest.mark.parametrize("clear_parameters", [True, False])
+def test_papermill(clear_parameters):
+    nb = _papermill_notebook()
+
+    stripped_notebook = _stripout_helper(
+        nb,
+        keep_output=True,
+        papermill=True,
+        papermill_clear_parameters=clear_parameters,
+    )
+
+    assert "papermill" not in stripped_notebook.metadata
+    assert len(stripped_notebook.cells) == len(executed_nb.cells)
+    assert all("papermill" not in cell.metadata for cell in stripped_notebook.cells)
+    parameters = stripped_notebook.cells[2]
+    assert parameters.source == executed_nb.cells[2].source
+    assert len(parameters.outputs) == (0 if clear_parameters else 1)
+
+
+def test_papermill_disabled():
+    nb = _papermill_notebook()
+
+    stripped_notebook = _stripout_helper(nb)
+
+    assert "papermill" in stripped_notebook.metadata
+    assert len(stripped_notebook.cells) == len(nb.cells)
+
+
+def test_notebook_overrides():
+    nb = deepcopy(executed_nb)
+    nb.metadata["nbstripout_fast"] = {
+        "keep_output": True,
+        "keep_count": True,
+        "keep_keys": ["metadata.kernelspec"],
+    }
+
+    stripped_notebook = _stripout_helper(nb, extra_keys=["metadata.kernelspec"])
+
+    assert stripped_notebook.cells[-3]["execution_count"] == 3
+    assert len(stripped_notebook.cells[-3]["outputs"]) == 1
+    assert "kernelspec" in stripped_notebook.metadata
+    # The settings stay in the notebook
+    assert stripped_notebook.metadata["nbstripout_fast"] == nb.metadata["nbstripout_fast"]
+
+
+@pytest.mark.parametrize(
+    "kwargs",
+    [dict(keep_only_metadata=["kernelspec"]), dict(extra_keys=["metadata.nbstripout_fast"])],
+)
+def test_notebook_overrides_removed_by_stripping(kwargs):
+    # The settings apply to both stripping and writing the notebook, even if stripping removes them
+    nb = deepcopy(executed_nb)
+    nb.metadata["nbstripout_fast"] = {"indent": 4, "keep_count": True}
+
+    contents = _stripout_contents(nbformat.writes(nb), **kwargs)
+
+    assert contents.startswith('{\n    "cells": [\n')
+    stripped_notebook = json.loads(contents)
+    assert "nbstripout_fast" not in stripped_notebook["metadata"]
+    assert stripped_notebook["cells"][-3]["execution_count"] == 3
+
+
+def test_notebook_overrides_disallowed():
+    nb = deepcopy(executed_nb)
+    nb.metadata["nbstripout_fast"] = {"keep_output": True}
+
+    stripped_notebook = _stripout_helper(nb, allow_notebook_overrides=False)
+
+    assert all(len(cell.get("outputs", [])) == 0 for cell in stripped_notebook.cells)
+
+
+def test_invalid_notebook_overrides():
+    nb = deepcopy(executed_nb)
+    nb.metadata["nbstripout_fast"] = {"keep_output": "yes"}
+
+    with pytest.raises(RuntimeError):
+        _stripout_helper(nb)
+
+
+def _output_types_notebook():
+    nb = nbformat.v4.new_notebook()
+    nb.cells = [
+        nbformat.v4.new_code_cell(
+            "import sys; print('out'); print('warning', file=sys.stderr); 1 / 0",
+            outputs=[
+                nbformat.v4.new_output("stream", name="stdout", text="out\n"),
+                nbformat.v4.new_output("stream", name="stderr", text="warning\n"),
+                nbformat.v4.new_output(
+                    "error", ename="ZeroDivisionError", evalue="division by zero", traceback=[]
+                ),
+            ],
+        )
+    ]
+    return nb
+
+
+def _output_types(nb):
+    return [(out["output_type"], out.get("name")) for out in nb.cells[0].outputs]
+
+
+def test_strip_output_types():
+    stripped_notebook = _stripout_helper(
+        _output_types_notebook(),
+        keep_output=True,
+        strip_output_types=["stream:stderr", "error"],
+    )
+
+    assert _output_types(stripped_notebook) == [("stream", "stdout")]
+
+
+def test_keep_output_types():
+    stripped_notebook = _stripout_helper(
+        _output_types_notebook(), keep_output_types=["stream:stdout", "error"]
+    )
+
+    assert _output_types(stripped_notebook) == [("stream", "stdout"), ("error", None)]
+
+
+@pytest.mark.parametrize("output_type", ["stream:foo", "widget", "error:x"])
+def test_invalid_output_types(output_type):
+    with pytest.raises(RuntimeError):
+        _stripout_helper(_output_types_notebook(), strip_output_types=[output_type])
+
+
+def _custom_output_notebook():
+    nb = _output_types_notebook()
+    nb.cells[0].outputs.append({"output_type": "custom", "data": {"text/plain": "?"}})
+    return nb
+
+
+@pytest.mark.parametrize(
+    "policy, n_outputs", [(None, 4), ("keep", 4), ("strip", 3)]
+)
+def test_unknown_output_types(policy, n_outputs):
+    stripped_notebook = _stripout_helper(
+        _custom_output_notebook(), keep_output=True, unknown_output_types=policy
+    )
+
+    assert len(stripped_notebook.cells[0].outputs) == n_outputs
+
+
+def test_unknown_output_types_error():
+    with pytest.raises(RuntimeError):
+        _stripout_helper(_custom_output_notebook(), unknown_output_types="error")
+
+
+def test_regex_error_and_update_display_data():
+    nb = _output_types_notebook()
+    nb.cells[0].outputs.append(
+        {
+            "output_type": "update_display_data",
+            "data": {"text/plain": "100% done"},
+            "metadata": {},
+            "transient": {"display_id": "progress"},
+        }
+    )
+
+    stripped_notebook = _stripout_helper(
+        nb, keep_output=True, strip_regex="^(100%.*|ZeroDivisionError: .*)$"
+    )
+
+    assert _output_types(stripped_notebook) == [("stream", "stdout"), ("stream", "stderr")]
+
+
+def test_regex_malformed_output():
+    nb = json.loads(nbformat.writes(_output_types_notebook()))
+    del nb["cells"][0]["outputs"][0]["text"]
+
+    stripped_notebook = _stripout_json(nb, keep_output=True, strip_regex="zz")
+
+    assert stripped_notebook["cells"][0]["outputs"][0] == {
+        "output_type": "stream",
+        "name": "stdout",
+    }
+
+
+@pytest.mark.parametrize(
+    "presets, stripped_metadata, stripped_cell_metadata",
+    [
+        (["colab"], ["colab"], ["id", "outputId"]),
+        (["kaggle"], ["kaggle"], ["trusted"]),
+        (["colab", "kaggle"], ["colab", "kaggle"], ["id", "trusted"]),
+    ],
+)
+def test_presets(presets, stripped_metadata, stripped_cell_metadata):
+    nb = deepcopy(clean_nb)
+    nb.metadata.update({"colab": {"name": "nb.ipynb"}, "kaggle": {}, "papermill": {}})
+    for cell in nb.cells:
+        cell.metadata.update({"id": "abc", "outputId": "def", "trusted": True, "papermill": {}})
+
+    stripped_notebook = _stripout_helper(nb, presets=presets)
+
+    for key in stripped_metadata:
+        assert key not in stripped_notebook.metadata
+    for cell in stripped_notebook.cells:
+        for key in stripped_cell_metadata:
+            assert key not in cell.metadata
+    # Presets only strip metadata, they do not turn on papermill mode
+    assert "papermill" in stripped_notebook.metadata
+
+
+def test_unknown_preset():
+    with pytest.raises(RuntimeError):
+        _stripout_helper(clean_nb, presets=["notepad"])
+
+
+@pytest.mark.parametrize(
+    "use_default_extra_keys, extra_keys, stripped",
+    [
+        (True, [], True),
+        (False, [], False),
+        # Keys that are listed are stripped, even if they are also default extra keys
+        (False, ["metadata.signature"], True),
+    ],
+)
+def test_use_default_extra_keys(use_default_extra_keys, extra_keys, stripped):
+    nb = deepcopy(clean_nb)
+    nb.metadata["signature"] = "abc"
+    nb.metadata["nbstripout_fast"] = {"use_default_extra_keys": use_default_extra_keys}
+
+    stripped_notebook = _stripout_helper(nb, extra_keys=extra_keys)
+
+    assert ("signature" not in stripped_notebook.metadata) == stripped
+
+
 def test_source_as_strings():
     stripped_notebook = nbformat.v4.reads(
         stripout(
@@ -155,6 +757,389 @@ def test_source_as_strings():
     assert copied_nb == stripped_notebook
 
 
+@pytest.mark.parametrize(
+    ("multiline_format", "expected_source", "expected_text", "expected_html"),
+    [
+        ("lines", ["x = 1\n", "x"], ["a\r\n", "b\n"], ["<b>\n", "</b>"]),
+        ("string", "x = 1\nx", "a\r\nb\n", "<b>\n</b>"),
+        ("preserve", "x = 1\nx", ["a\r\n", "b\n"], "<b>\n</b>"),
+    ],
+)
+def test_multiline_format(
+    multiline_format, expected_source, expected_text, expected_html
+):
+    nb = deepcopy(clean_nb)
+    nb.cells[1].source = "x = 1\nx"
+    nb.cells[1].outputs = [
+        nbformat.v4.new_output("stream", name="stdout", text=["a\r\n", "b\n"]),
+        nbformat.v4.new_output(
+            "display_data", data={"text/html": "<b>\n</b>", "image/png": "AAAA\nBBBB"}
+        ),
+    ]
+
+    # Not using nbformat as it converts everything to strings
+    stripped_notebook = json.loads(
+        stripout(
+            json.dumps(nb),
+            keep_output=True,
+            keep_count=False,
+            extra_keys=DEFAULT_EXTRA_KEYS,
+            drop_empty_cells=False,
+            settings={"multiline_format": multiline_format},
+        )
+    )
+
+    cell = stripped_notebook["cells"][1]
+    assert cell["source"] == expected_source
+    assert cell["outputs"][0]["text"] == expected_text
+    assert cell["outputs"][1]["data"]["text/html"] == expected_html
+    # Binary data is never split
+    assert cell["outputs"][1]["data"]["image/png"] == "AAAA\nBBBB"
+
+
+@pytest.mark.parametrize(
+    "dumps_kwargs",
+    [
+        dict(indent=1, ensure_ascii=False),
+        dict(indent=2),
+        dict(indent=4, ensure_ascii=False),
+        dict(indent="\t"),
+        dict(separators=(",", ":"), ensure_ascii=False),
+    ],
+)
+def test_style_preserved(dumps_kwargs):
+    nb = deepcopy(clean_nb)
+    nb.cells[0].source = "# Café ☕"
+    contents = json.dumps(nb, **dumps_kwargs) + "\n"
+
+    assert _stripout_contents(contents) == contents
+
+
+@pytest.mark.parametrize(
+    "indent, expected_start", [(2, '{\n  "'), ("4", '{\n    "'), ("compact", '{"')]
+)
+def test_indent(indent, expected_start):
+    contents = json.dumps(clean_nb, indent=1)
+
+    assert _stripout_contents(contents, indent=indent).startswith(expected_start)
+
+
+@pytest.mark.parametrize("bom", ["", "\ufeff"])
+@pytest.mark.parametrize(
+    "eol, expected_eol", [(None, "\r\n"), ("preserve", "\r\n"), ("lf", "\n"), ("crlf", "\r\n")]
+)
+def test_eol_and_bom(bom, eol, expected_eol):
+    contents = json.dumps(clean_nb, indent=1, ensure_ascii=False) + "\n"
+
+    stripped = _stripout_contents(bom + contents.replace("\n", "\r\n"), eol=eol)
+
+    assert stripped == bom + contents.replace("\n", expected_eol)
+
+
+# Each golden notebook comes with the same notebook as written by nbformat
+@pytest.mark.parametrize(
+    "name", sorted(p.stem for p in GOLDEN_DIR.glob("*.ipynb") if ".nbformat" not in p.suffixes)
+)
+def test_nbformat_compatible(name):
+    contents = (GOLDEN_DIR / f"{name}.ipynb").read_text(encoding="utf-8")
+    expected = (GOLDEN_DIR / f"{name}.nbformat.ipynb").read_text(encoding="utf-8")
+    kwargs = dict(keep_output=True, keep_count=True, extra_keys=[], nbformat_compatible=True)
+
+    nb = nbformat.reads(contents, as_version=nbformat.NO_CONVERT)
+    assert nbformat.writes(nb) + "\n" == expected
+    assert _stripout_contents(contents, **kwargs) == expected
+    assert _stripout_contents(expected, **kwargs) == expected
+
+
+def _v3_notebook():
+    return {
+        "metadata": {"name": "", "signature": "sha256:abc"},
+        "nbformat": 3,
+        "nbformat_minor": 0,
+        "worksheets": [
+            {
+                "cells": [
+                    {"cell_type": "heading", "level": 1, "metadata": {}, "source": ["Title"]},
+                    {
+                        "cell_type": "code",
+                        "collapsed": False,
+                        "input": ["1 + 1"],
+                        "language": "python",
+                        "metadata": {"scrolled": True},
+                        "outputs": [
+                            {"metadata": {}, "output_type": "pyout", "prompt_number": 1, "text": ["2"]},
+                            {"output_type": "stream", "stream": "stderr", "text": ["warning\n"]},
+                        ],
+                        "prompt_number": 1,
+                    },
+                    {"cell_type": "code", "input": [], "language": "python", "metadata": {}, "outputs": []},
+                ],
+                "metadata": {},
+            }
+        ],
+    }
+
+
+def _stripout_contents(
+    contents,
+    *,
+    keep_output=False,
+    keep_count=False,
+    extra_keys=DEFAULT_EXTRA_KEYS,
+    drop_empty_cells=False,
+    strip_regex=None,
+    zeppelin=False,
+    **settings,
+):
+    return stripout(
+        contents,
+        keep_output=keep_output,
+        keep_count=keep_count,
+        extra_keys=extra_keys,
+        drop_empty_cells=drop_empty_cells,
+        strip_regex=strip_regex,
+        settings=settings,
+        zeppelin=zeppelin,
+    )
+
+
+def _stripout_json(nb, **kwargs):
+    return json.loads(_stripout_contents(json.dumps(nb), **kwargs))
+
+
+def test_v3():
+    stripped = _stripout_json(_v3_notebook())
+
+    assert stripped["nbformat"] == 3
+    assert "signature" not in stripped["metadata"]
+    cell = stripped["worksheets"][0]["cells"][1]
+    assert cell["prompt_number"] is None
+    assert cell["outputs"] == []
+    assert "scrolled" not in cell["metadata"]
+
+
+def test_v3_keep_output():
+    stripped = _stripout_json(
+        _v3_notebook(),
+        keep_output=True,
+        drop_empty_cells=True,
+        strip_output_types=["stream:stderr"],
+    )
+
+    cells = stripped["worksheets"][0]["cells"]
+    assert len(cells) == 2
+    assert cells[1]["outputs"] == [
+        {"metadata": {}, "output_type": "pyout", "prompt_number": None, "text": ["2"]}
+    ]
+
+    stripped = _stripout_json(_v3_notebook(), keep_output=True, strip_regex="^2$")
+    assert [out["output_type"] for out in stripped["worksheets"][0]["cells"][1]["outputs"]] == [
+        "stream"
+    ]
+
+
+def test_v3_output_keys():
+    # Output keys name output types as in nbformat 4
+    stripped = _stripout_json(
+        _v3_notebook(), keep_output=True, extra_keys=["output:execute_result.metadata"]
+    )
+
+    outputs = stripped["worksheets"][0]["cells"][1]["outputs"]
+    assert "metadata" not in outputs[0]
+    assert outputs[0]["output_type"] == "pyout"
+
+
+def test_v3_upgrade():
+    stripped_notebook = nbformat.reads(
+        json.dumps(_stripout_json(_v3_notebook(), keep_output=True, upgrade_v3=True)),
+        as_version=nbformat.NO_CONVERT,
+    )
+
+    assert stripped_notebook.nbformat == 4
+    nbformat.validate(stripped_notebook)
+    assert stripped_notebook.cells[0].cell_type == "markdown"
+    assert stripped_notebook.cells[0].source == "# Title"
+    outputs = stripped_notebook.cells[1].outputs
+    assert outputs[0].output_type == "execute_result"
+    assert outputs[0].data == {"text/plain": "2"}
+    assert outputs[1].name == "stderr"
+
+
+def test_v3_upgrade_display_data_prompt_number():
+    nb = _v3_notebook()
+    outputs = nb["worksheets"][0]["cells"][1]["outputs"]
+    outputs.append(
+        {"metadata": {}, "output_type": "display_data", "prompt_number": 1, "text": ["3"]}
+    )
+
+    stripped = _stripout_json(nb, keep_output=True, upgrade_v3=True)
+
+    # nbformat.v4.upgrade moves the prompt_number into the data instead
+    assert stripped["cells"][1]["outputs"][2] == {
+        "output_type": "display_data",
+        "data": {"text/plain": ["3"]},
+        "metadata": {},
+    }
+    nbformat.validate(stripped)
+
+
+def _zeppelin_note():
+    paragraph = {
+        "text": "%python\nprint(1)",
+        "config": {},
+        "results": {
+            "code": "SUCCESS",
+            "msg": [{"type": "TEXT", "data": "1\n"}, {"type": "TEXT", "data": "100% done"}],
+        },
+        "runtimeInfos": {},
+        "progress": 0,
+        "id": "paragraph_1",
+        "dateCreated": "2024-01-01 10:00:00.000",
+        "dateStarted": "2024-01-01 10:00:01.000",
+        "dateFinished": "2024-01-01 10:00:02.000",
+        "dateUpdated": "2024-01-01 10:00:00.000",
+        "status": "FINISHED",
+    }
+    empty_paragraph = {"text": "", "results": {}, "id": "paragraph_2", "status": "READY"}
+    return {"paragraphs": [paragraph, empty_paragraph], "name": "note", "id": "NOTE", "config": {}}
+
+
+def test_zeppelin():
+    stripped = _stripout_json(_zeppelin_note())
+
+    paragraph = stripped["paragraphs"][0]
+    assert paragraph["results"] == {}
+    for key in ["dateStarted", "dateFinished", "dateUpdated", "runtimeInfos", "progress", "status"]:
+        assert key not in paragraph
+    assert paragraph["dateCreated"] == "2024-01-01 10:00:00.000"
+    assert len(stripped["paragraphs"]) == 2
+
+
+def test_zeppelin_keep_output():
+    stripped = _stripout_json(
+        _zeppelin_note(), keep_output=True, strip_regex="^100%", drop_empty_cells=True
+    )
+
+    assert len(stripped["paragraphs"]) == 1
+    assert stripped["paragraphs"][0]["results"]["msg"] == [{"type": "TEXT", "data": "1\n"}]
+
+
+def test_zeppelin_forced():
+    with pytest.raises(RuntimeError):
+        _stripout_json({"cells": []}, zeppelin=True)
+
+
+@pytest.mark.parametrize(
+    "setting, message",
+    [
+        ("validate", "can not be validated"),
+        ("validate_output", "can not be validated"),
+        ("repair", "can not be repaired"),
+    ],
+)
+def test_zeppelin_validate_and_repair(setting, message):
+    with pytest.raises(RuntimeError, match=f"Zeppelin notes {message}"):
+        _stripout_json(_zeppelin_note(), **{setting: True})
+
+
+@pytest.mark.parametrize("keep_output", [True, False])
+def test_validate_output(keep_output):
+    # Stripping must never make a valid notebook invalid
+    nbformat.validate(executed_nb)
+    stripped = _stripout_helper(
+        executed_nb, keep_output=keep_output, validate=True, validate_output=True
+    )
+
+    nbformat.validate(stripped)
+
+
+@pytest.mark.parametrize(
+    "path, value, expected",
+    [
+        ("cells/1/execution_count", -1, "/cells/1/execution_count (cell 1)"),
+        ("cells/1/id", "not valid", "/cells/1/id (cell 1)"),
+        ("cells/0/outputs", [], "/cells/0 (cell 0): Additional properties"),
+        ("metadata/kernelspec", {"name": "python3"}, "/metadata/kernelspec: 'display_name'"),
+    ],
+)
+def test_validate(path, value, expected):
+    nb = json.loads(nbformat.writes(clean_nb))
+    *parents, key = path.split("/")
+    target = nb
+    for parent in parents:
+        target = target[int(parent) if parent.isdigit() else parent]
+    target[key] = value
+    with pytest.raises(nbformat.ValidationError):
+        nbformat.validate(nb)
+
+    # Only checked when asked to
+    _stripout_json(nb)
+    with pytest.raises(RuntimeError, match=re.escape(expected)):
+        _stripout_json(nb, validate=True)
+
+
+def test_validate_minor_version():
+    # Cells only have ids from nbformat 4.5
+    nb = json.loads(nbformat.writes(clean_nb))
+    nb["nbformat_minor"] = 4
+    with pytest.raises(nbformat.ValidationError):
+        nbformat.validate(nb)
+
+    with pytest.raises(RuntimeError, match=re.escape("/cells/0 (cell 0): Additional properties")):
+        _stripout_json(nb, validate=True)
+
+    for cell in nb["cells"]:
+        del cell["id"]
+    nbformat.validate(nb)
+    _stripout_json(nb, validate=True)
+
+
+def test_validate_v3_upgrade():
+    # Only nbformat 4 notebooks can be validated, so v3 notebooks are validated once upgraded
+    with pytest.raises(RuntimeError, match="only nbformat 4"):
+        _stripout_json(_v3_notebook(), validate=True)
+
+    stripped = _stripout_json(_v3_notebook(), validate=True, upgrade_v3=True)
+    nbformat.validate(stripped)
+
+
+def _malformed_notebook():
+    nb = json.loads(nbformat.writes(clean_nb))
+    nb["metadata"] = None
+    del nb["cells"][1]["outputs"]
+    del nb["cells"][2]["execution_count"]
+    nb["cells"][0]["outputs"] = []
+    nb["cells"][3]["metadata"] = None
+    nb["cells"][4]["source"] = None
+    nb["cells"][5]["source"] = ["x", 3]
+    return nb
+
+
+@pytest.mark.parametrize(
+    "kwargs", [dict(), dict(drop_empty_cells=True), dict(keep_output=True)]
+)
+def test_malformed_notebook_fails(kwargs):
+    with pytest.raises(RuntimeError):
+        _stripout_json(_malformed_notebook(), **kwargs)
+
+
+def test_repair():
+    with pytest.warns(UserWarning) as record:
+        stripped = _stripout_json(_malformed_notebook(), repair=True, validate_output=True)
+
+    assert [str(w.message) for w in record] == [
+        "Repaired /: replaced null metadata with {}",
+        "Repaired /cells/0: removed outputs from a cell that is not a code cell",
+        "Repaired /cells/1: added the missing outputs",
+        "Repaired /cells/2: added the missing execution_count",
+        "Repaired /cells/3: replaced null metadata with {}",
+        'Repaired /cells/4: replaced null source with ""',
+        "Repaired /cells/5/source: converted lines that were not strings",
+    ]
+    nbformat.validate(stripped)
+    assert stripped["cells"][5]["source"] == ["x", "3"]
+
+
 @pytest.mark.parametrize(
     "keep_output",
     [
@@ -254,3 +1239,79 @@ def test_regex2(strip_regex, n_outputs_matched, keep_output, widget_notebook):
         assert len(stripped["cells"][i]["outputs"]) == (
             n_output - n_matched if keep_output else 0
         )
+
+
+WIDGET_VIEW = "application/vnd.jupyter.widget-view+json"
+WIDGET_STATE = "application/vnd.jupyter.widget-state+json"
+
+
+def _widget_outputs(nb):
+    return [
+        out
+        for cell in nb["cells"]
+        for out in cell["outputs"]
+        if WIDGET_VIEW in out.get("data", {})
+    ]
+
+
+def test_widget_policy_keep(widget_notebook):
+    stripped = _stripout_helper(widget_notebook, keep_output=True)
+
+    assert len(_widget_outputs(stripped)) == 2
+    assert "widgets" not in stripped.metadata
+
+
+def test_widget_policy_drop_orphans(widget_notebook):
+    # The default extra keys strip the widget state, so every widget output is orphaned
+    stripped = _stripout_helper(
+        widget_notebook, keep_output=True, widget_policy="drop_orphans"
+    )
+    assert _widget_outputs(stripped) == []
+    assert sum(len(cell["outputs"]) for cell in stripped["cells"]) == 3
+
+    stripped = _stripout_helper(
+        widget_notebook,
+        keep_output=True,
+        keep_keys=["metadata.widgets"],
+        widget_policy="drop_orphans",
+    )
+    assert len(_widget_outputs(stripped)) == 2
+
+
+def test_widget_policy_prune_state(widget_notebook):
+    stripped = _stripout_helper(
+        widget_notebook,
+        keep_output=True,
+        keep_keys=["metadata.widgets"],
+        widget_policy="prune_state",
+    )
+
+    state = stripped.metadata["widgets"][WIDGET_STATE]["state"]
+    original_state = widget_notebook.metadata["widgets"][WIDGET_STATE]["state"]
+    assert 0 < len(state) < len(original_state)
+    for out in _widget_outputs(stripped):
+        model_id = out["data"][WIDGET_VIEW]["model_id"]
+        # Both the Output models and the layouts they reference are kept
+        assert model_id in state
+        layout = state[model_id]["state"]["layout"]
+        assert layout.removeprefix("IPY_MODEL_") in state
+
+
+def test_widget_policy_text_fallback(widget_notebook):
+    stripped = _stripout_helper(
+        widget_notebook, keep_output=True, widget_policy="text_fallback"
+    )
+
+    assert _widget_outputs(stripped) == []
+    fallbacks = [
+        out["data"]
+        for cell in stripped["cells"]
+        for out in cell["outputs"]
+        if out["output_type"] == "display_data"
+    ]
+    assert fallbacks == [{"text/plain": "Output()"}] * 2
+
+
+def test_invalid_widget_policy(widget_notebook):
+    with pytest.raises(RuntimeError):
+        _stripout_helper(widget_notebook, widget_policy="bogus")